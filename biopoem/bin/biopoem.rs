@@ -10,9 +10,13 @@ extern crate chrono;
 mod cmd;
 
 use cmd::client;
+use cmd::collect;
 use cmd::deployer;
 use cmd::query;
+use cmd::samples;
 use cmd::server;
+use cmd::stop;
+use cmd::validate;
 use structopt::StructOpt;
 
 /// A suite of programs for handling big omics data.
@@ -42,6 +46,14 @@ enum SubCommands {
   Deployer(deployer::Arguments),
   #[structopt(name = "query")]
   Query(query::Arguments),
+  #[structopt(name = "collect")]
+  Collect(collect::Arguments),
+  #[structopt(name = "stop")]
+  Stop(stop::Arguments),
+  #[structopt(name = "samples")]
+  Samples(samples::Arguments),
+  #[structopt(name = "validate")]
+  Validate(validate::Arguments),
 }
 
 fn main() {
@@ -60,5 +72,17 @@ fn main() {
     SubCommands::Client(arguments) => {
       client::run(&arguments);
     }
+    SubCommands::Collect(arguments) => {
+      collect::run(&arguments);
+    }
+    SubCommands::Stop(arguments) => {
+      stop::run(&arguments);
+    }
+    SubCommands::Samples(arguments) => {
+      samples::run(&arguments);
+    }
+    SubCommands::Validate(arguments) => {
+      validate::run(&arguments);
+    }
   }
 }
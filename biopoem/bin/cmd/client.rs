@@ -1,5 +1,8 @@
 use biopoem_api::{self, client};
+use biopoem_api::client::model::{RunStatus, SharedStatus};
+use biopoem_api::client::resume;
 use factotum::{execute_dag, is_valid_url};
+use poem::listener::{Listener, RustlsCertificate, RustlsConfig};
 use poem::{
   error::NotFoundError, http::StatusCode, listener::TcpListener, EndpointExt, Response, Server,
 };
@@ -30,13 +33,25 @@ pub struct Arguments {
   #[structopt(name = "dag", short = "d", long = "dag")]
   dag: String,
 
-  /// Secret key.
-  #[structopt(name = "secret_key", short = "k", long = "secret_key")]
+  /// Secret key, all requests must carry it as the secret_key parameter. It is read from BIOPOEM_SECRET_KEY if not given, which keeps it off the command line.
+  #[structopt(name = "secret_key", short = "k", long = "secret_key", env = "BIOPOEM_SECRET_KEY", hide_env_values = true)]
   secret_key: String,
 
   /// Url of the dag file.
   #[structopt(name = "webhook", short = "W", long = "webhook", default_value = "")]
   webhook: String,
+
+  /// The certificate of the client in PEM, the client serves https with --tls-key.
+  #[structopt(name = "tls-cert", long = "tls-cert", requires = "tls-key")]
+  tls_cert: Option<String>,
+
+  /// The private key of the certificate in PEM.
+  #[structopt(name = "tls-key", long = "tls-key", requires = "tls-cert")]
+  tls_key: Option<String>,
+
+  /// Run all tasks again and truncate the logs, the tasks which succeeded before a restart are skipped by default.
+  #[structopt(name = "fresh", long = "fresh")]
+  fresh: bool,
 }
 
 pub async fn download_dag_file(dag_file_url: &str, destfile: &str) {
@@ -75,7 +90,7 @@ pub async fn run(args: &Arguments) {
     _ => {}
   };
 
-  if let Err(log) = init_file_logger("Client", "client.log") {
+  if let Err(log) = init_file_logger("Client", "client.log", !args.fresh) {
     error!(target:"stdout", "Log initialization error, {}", log);
     process::exit(biopoem_api::PROC_OTHER_ERROR);
   };
@@ -97,22 +112,104 @@ pub async fn run(args: &Arguments) {
     }
   }
 
+  // The status of a previous run in the workdir is stale.
+  match fs::remove_file("status") {
+    _ => {}
+  };
+
+  let checked = match args.fresh {
+    true => resume::clear().and_then(|_| resume::check_factfile(destfile)),
+    false => resume::check_factfile(destfile),
+  };
+  if let Err(msg) = checked {
+    error!(target:"stdout", "{}", msg);
+    process::exit(biopoem_api::PROC_OTHER_ERROR);
+  }
+
+  let done = resume::done_tasks();
+  let status = match RunStatus::from_factfile(destfile) {
+    Err(msg) => {
+      error!(target:"stdout", "{}", msg);
+      process::exit(biopoem_api::PROC_OTHER_ERROR);
+    }
+    Ok(mut status) => {
+      status.mark_done(&done);
+      SharedStatus::new(status)
+    }
+  };
+
+  // Factotum only runs the tasks which haven't succeeded before a restart.
+  let factfile = match done.len() {
+    0 => destfile,
+    _ => {
+      let resumefile = "dag.resume.factfile";
+      match resume::resume_factfile(destfile, &done, resumefile) {
+        Err(msg) => {
+          error!(target:"stdout", "{}", msg);
+          process::exit(biopoem_api::PROC_OTHER_ERROR);
+        }
+        Ok(left) => {
+          info!(target:"stdout", "Resume the DAG, skip {} succeeded tasks, {} tasks left.", done.len(), left);
+          match left {
+            0 => "",
+            _ => resumefile,
+          }
+        }
+      }
+    }
+  };
+
+  let tls = match (&args.tls_cert, &args.tls_key) {
+    (Some(cert), Some(key)) => match (fs::read(cert), fs::read(key)) {
+      (Ok(cert), Ok(key)) => Some(RustlsCertificate::new().cert(cert).key(key)),
+      (Err(msg), _) | (_, Err(msg)) => {
+        error!(target:"stdout", "Cannot read the certificate or the key, {}", msg);
+        process::exit(biopoem_api::PROC_OTHER_ERROR);
+      }
+    },
+    _ => None,
+  };
+
+  // Factotum reports task states to the client itself, the client forwards them to the webhook.
+  // With TLS, it reports to a plain listener on 127.0.0.1 since it doesn't trust the CA.
+  let internal_port = match tls {
+    Some(_) => match std::net::TcpListener::bind("127.0.0.1:0").and_then(|l| l.local_addr()) {
+      Err(msg) => {
+        error!(target:"stdout", "Cannot find a free port for the webhook, {}", msg);
+        process::exit(biopoem_api::PROC_OTHER_ERROR);
+      }
+      Ok(addr) => addr.port().to_string(),
+    },
+    None => args.port.clone(),
+  };
+  let internal_webhook_url = format!(
+    "http://127.0.0.1:{}/api/v1/webhook?secret_key={}",
+    internal_port, args.secret_key
+  );
+  let webhook_url: Option<String> = match &args.webhook == "" {
+    true => None,
+    false => Some(args.webhook.clone()),
+  };
+
   let dag = args.dag.clone();
-  let webhook_url = args.webhook.clone();
-  tokio::spawn(async move {
-    info!(target:"stdout", "Launch DAG engine with {}", &dag);
-    let webhook_url: Option<String> = match &webhook_url == "" {
-      true => None,
-      false => Some(webhook_url),
+  let dag_status = status.clone();
+  // execute_dag blocks until all tasks finish, keep it away from the workers serving requests.
+  tokio::task::spawn_blocking(move || {
+    let exit_code = match factfile {
+      "" => {
+        info!(target:"stdout", "All tasks of {} succeeded before, nothing to run.", &dag);
+        biopoem_api::PROC_SUCCESS
+      }
+      _ => {
+        info!(target:"stdout", "Launch DAG engine with {}", &dag);
+        execute_dag(factfile, Some(internal_webhook_url))
+      }
     };
-    let exit_code = execute_dag(destfile, webhook_url);
+    dag_status.update(|status| status.finish(exit_code));
 
     let statusfile = "status";
 
-    let status = match exit_code == 0 {
-      false => "Failed",
-      true => "Success",
-    };
+    let status = dag_status.get().state;
 
     match fs::write(statusfile, status.to_string()) {
       Err(msg) => error!("Cannot write status, {}", msg),
@@ -120,17 +217,32 @@ pub async fn run(args: &Arguments) {
     };
   });
 
-  info!(target:"stdout", "Launch client on {}:{}", &args.host[..], &args.port[..]);
-  let route = client::route::init_route().catch_error(|err: NotFoundError| async {
-    Response::builder()
-      .status(StatusCode::NOT_FOUND)
-      .body("Not found")
-  });
-
-  if let Err(err) = Server::new(TcpListener::bind(format!("{}:{}", args.host, args.port)))
-    .run(route)
-    .await
-  {
+  let scheme = match tls {
+    Some(_) => "https",
+    None => "http",
+  };
+  info!(target:"stdout", "Launch client on {}://{}:{}", scheme, &args.host[..], &args.port[..]);
+  let route = client::route::init_route()
+    .data(client::handler::SecretKey(args.secret_key.clone()))
+    .data(client::handler::Webhook(webhook_url))
+    .data(status)
+    .catch_error(|err: NotFoundError| async {
+      Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body("Not found")
+    });
+
+  let listener = TcpListener::bind(format!("{}:{}", args.host, args.port));
+  let result = match tls {
+    Some(certificate) => {
+      let internal = TcpListener::bind(format!("127.0.0.1:{}", internal_port));
+      Server::new(listener.rustls(RustlsConfig::new().fallback(certificate)).combine(internal))
+        .run(route)
+        .await
+    }
+    None => Server::new(listener).run(route).await,
+  };
+  if let Err(err) = result {
     error!("{}", err);
     process::exit(biopoem_api::PROC_EXEC_ERROR);
   }
@@ -0,0 +1,204 @@
+use super::{init_logger, ssh_options, write_ssh_config, SshArgs};
+use biopoem_api::server::collect::{self, Entry, Manifest};
+use biopoem_api::server::remote::SshOptions;
+use biopoem_api::{server, server::host::Host, server::remote};
+use futures::stream::{self, StreamExt};
+use prettytable::Table;
+use std::path::Path;
+use std::{env, fs, process};
+use structopt::StructOpt;
+
+/// Collect results from remote machines for Biopoem
+#[derive(StructOpt, PartialEq, Debug)]
+#[structopt(setting=structopt::clap::AppSettings::ColoredHelp, name="Biopoem - Collect", author="Jingcheng Yang <yjcyxky@163.com>")]
+pub struct Arguments {
+  /// Which working directory for saving data.
+  #[structopt(name = "workdir", short = "w", long = "workdir", default_value = ".")]
+  workdir: String,
+
+  /// The host file.
+  #[structopt(name = "hosts", short = "-H", long = "hosts", default_value = "hosts")]
+  hosts: String,
+
+  #[structopt(flatten)]
+  ssh: SshArgs,
+
+  /// The working directory on remote machine.
+  #[structopt(
+    name = "remote-workdir",
+    short = "r",
+    long = "remote-workdir",
+    default_value = "/mnt/biopoem"
+  )]
+  remote_workdir: String,
+
+  /// Output files to collect, globs relative to the remote working directory, such as "*.bam". Only letters, digits and ._-+=,@%:/*?[]! are allowed.
+  #[structopt(name = "outputs", short = "o", long = "outputs")]
+  outputs: Vec<String>,
+
+  /// How many hosts are collected at the same time.
+  #[structopt(
+    name = "concurrency",
+    short = "c",
+    long = "concurrency",
+    default_value = "10"
+  )]
+  concurrency: usize,
+}
+
+async fn fetch(
+  session: &openssh::Session,
+  remote_workdir: &str,
+  globs: &Vec<String>,
+  subdir: &Path,
+  manifest: &mut Manifest,
+) -> Result<(), String> {
+  let files = remote::list_files(session, remote_workdir, globs).await?;
+  for (name, size) in files {
+    let local_path = collect::local_path(subdir, &name);
+    if let Some(parent) = local_path.parent() {
+      fs::create_dir_all(parent)
+        .map_err(|msg| format!("Cannot create the directory {}, {}", parent.display(), msg))?;
+    }
+
+    let remote_path = collect::remote_path(remote_workdir, &name);
+    // One broken file shouldn't stop collecting others, it is recorded in the manifest.
+    let resumable = collect::is_resumable(&name);
+    match remote::download_file(session, &remote_path, &local_path, size, resumable).await {
+      Err(msg) => {
+        error!("{}", msg);
+        manifest.errors.push(msg);
+      }
+      Ok(transfer) => manifest.files.push(Entry {
+        path: name,
+        size: size,
+        transfer: transfer.to_string(),
+        fetched_at: chrono::Local::now().to_rfc3339(),
+      }),
+    }
+  }
+
+  Ok(())
+}
+
+async fn collect_host(
+  host: &Host,
+  ssh: &SshOptions,
+  remote_workdir: &str,
+  globs: &Vec<String>,
+) -> Manifest {
+  let mut manifest = Manifest::new(host.hostname(), remote_workdir);
+  let subdir = Path::new("results").join(host.hostname());
+  biopoem_api::makedir(&subdir.to_string_lossy());
+
+  let port = match host.port().parse::<u16>() {
+    Err(msg) => {
+      manifest
+        .errors
+        .push(format!("Invalid port {}, {}", host.port(), msg));
+      return manifest;
+    }
+    Ok(port) => port,
+  };
+
+  match remote::init_session(host.ipaddr(), port, host.username(), ssh).await {
+    Err(msg) => {
+      manifest
+        .errors
+        .push(format!("Cannot connect {}, {}", host.ipaddr(), msg));
+    }
+    Ok(session) => {
+      if let Err(msg) = fetch(&session, remote_workdir, globs, &subdir, &mut manifest).await {
+        manifest.errors.push(msg);
+      }
+
+      match session.close().await {
+        Err(msg) => warn!("{}", msg),
+        _ => {}
+      };
+    }
+  }
+
+  if let Err(msg) = manifest.write(&subdir) {
+    error!("{}", msg);
+  }
+
+  manifest
+}
+
+fn print_summary(manifests: &Vec<Manifest>) {
+  let mut table = Table::new();
+  table.add_row(row!["hostname", "files", "bytes", "result", "reason"]);
+
+  for manifest in manifests {
+    let bytes: u64 = manifest.files.iter().map(|entry| entry.size).sum();
+    let result = match manifest.errors.len() {
+      0 => "Success",
+      _ => "Failed",
+    };
+    table.add_row(row![
+      manifest.hostname,
+      manifest.files.len(),
+      bytes,
+      result,
+      manifest.errors.join("; ")
+    ]);
+  }
+
+  table.printstd();
+}
+
+#[tokio::main]
+pub async fn run(args: &Arguments) {
+  let workdir = &args.workdir;
+  biopoem_api::makedir(workdir);
+
+  if let Err(log) = init_logger("Collect") {
+    error!(target:"stdout", "Log initialization error, {}", log);
+    process::exit(biopoem_api::PROC_OTHER_ERROR);
+  };
+
+  let ssh = ssh_options(&args.ssh, workdir);
+
+  info!("Set the current working directory to {}", &workdir);
+  match env::set_current_dir(&workdir) {
+    Err(msg) => {
+      println!("Cannot set working directory {}.", &msg);
+      process::exit(biopoem_api::PROC_OTHER_ERROR);
+    }
+    _ => {}
+  };
+
+  let mut globs: Vec<String> = collect::DEFAULT_FILES
+    .iter()
+    .map(|name| name.to_string())
+    .collect();
+  globs.extend(args.outputs.iter().cloned());
+  for glob in &args.outputs {
+    if let Err(msg) = collect::check_glob(glob) {
+      error!("{}", msg);
+      process::exit(biopoem_api::PROC_PARSE_ERROR);
+    }
+  }
+
+  let hosts = server::host::read_hosts(&args.hosts);
+  write_ssh_config(&ssh, &hosts);
+  let (ssh, globs, remote_workdir) = (&ssh, &globs, &args.remote_workdir);
+  let mut manifests: Vec<(usize, Manifest)> = stream::iter(hosts.iter().enumerate())
+    .map(|(index, host)| async move {
+      (
+        index,
+        collect_host(host, ssh, remote_workdir, globs).await,
+      )
+    })
+    .buffer_unordered(args.concurrency.max(1))
+    .collect()
+    .await;
+  manifests.sort_by_key(|(index, _)| *index);
+  let manifests: Vec<Manifest> = manifests.into_iter().map(|(_, manifest)| manifest).collect();
+
+  print_summary(&manifests);
+  if manifests.iter().any(|manifest| manifest.errors.len() > 0) {
+    process::exit(biopoem_api::PROC_EXEC_ERROR);
+  }
+}
@@ -1,5 +1,5 @@
 use super::{exists_exit, init_logger, notexists_exit};
-use biopoem_api::{self, deployer};
+use biopoem_api::{self, deployer, deployer::provider};
 use std::path::Path;
 use std::path::PathBuf;
 use std::{env, fs, process};
@@ -31,6 +31,10 @@ pub struct Arguments {
   )]
   template: String,
 
+  /// Cloud platform.
+  #[structopt(name = "provider", short = "P", long = "provider", possible_values=&provider::PROVIDERS, default_value = "alicloud")]
+  provider: String,
+
   /// Region, such as cn-shanghai
   #[structopt(name = "region", short = "r", long = "region")]
   region: String,
@@ -39,32 +43,38 @@ pub struct Arguments {
   #[structopt(name = "zone", short = "z", long = "zone", default_value = "a")]
   zone: String,
 
-  /// Instance Type
-  #[structopt(
-    name = "instance-type",
-    short = "i",
-    long = "instance-type",
-    default_value = "ecs.t6-c2m1.large"
-  )]
-  instance_type: String,
+  /// Instance Type, such as ecs.t6-c2m1.large for alicloud and t3.medium for aws by default.
+  #[structopt(name = "instance-type", short = "i", long = "instance-type")]
+  instance_type: Option<String>,
 
-  /// Image
-  #[structopt(
-    name = "image",
-    short = "I",
-    long = "image",
-    default_value = "ubuntu_20_04_x64_20G_alibase_20220215.vhd"
-  )]
-  image: String,
+  /// Image, such as ubuntu_20_04_x64_20G_alibase_20220215.vhd for alicloud by default.
+  #[structopt(name = "image", short = "I", long = "image")]
+  image: Option<String>,
+
+  /// The user for logging into servers, it depends on the provider by default (root for alicloud).
+  #[structopt(name = "username", short = "U", long = "username")]
+  username: Option<String>,
 
-  /// AccessKey.
+  /// AccessKey (OS_USERNAME for openstack).
   #[structopt(name = "access-key", short = "k", long = "access-key")]
   access_key: String,
 
-  /// SecretKey.
+  /// SecretKey (OS_PASSWORD for openstack).
   #[structopt(name = "secret-key", short = "s", long = "secret-key")]
   secret_key: String,
 
+  /// The CIDR block of the vswitch (subnet), private IPs are allocated from it.
+  #[structopt(name = "cidr", short = "C", long = "cidr", default_value = "172.16.0.0/21")]
+  cidr: String,
+
+  /// Let the cloud platform assign private IPs.
+  #[structopt(name = "auto-ip", short = "A", long = "auto-ip")]
+  auto_ip: bool,
+
+  /// Generate a new host key for each instance, templates pass them to instances by user data as host_keys (host_keys[i].private_key and host_keys[i].public_key belong to hostnames[i]), the host keys are saved into the known_hosts file for the server subcommand.
+  #[structopt(name = "host-keys", short = "K", long = "host-keys")]
+  host_keys: bool,
+
   /// Activate destroy mode.
   #[structopt(name = "destroy", short = "d", long = "destroy")]
   destroy: bool,
@@ -84,6 +94,8 @@ pub async fn run(args: &Arguments) {
     process::exit(biopoem_api::PROC_OTHER_ERROR);
   };
 
+  let provider = provider::get_provider(&args.provider).unwrap();
+
   // Deploy servers by terraform
   let subdir = "terraform";
   biopoem_api::makedir(&subdir);
@@ -93,6 +105,7 @@ pub async fn run(args: &Arguments) {
     if let Some(destroy_output) = deployer::run(
       "destroy",
       subdir,
+      provider.as_ref(),
       &args.access_key,
       &args.secret_key,
       &args.region,
@@ -123,14 +136,41 @@ pub async fn run(args: &Arguments) {
       &format!("The file {} exists!", destfile.display()),
     );
     let template = fs::read_to_string(&template).unwrap();
-    let data = deployer::Config::new(
+    let image = match args.image.as_deref().or(provider.default_image()) {
+      Some(image) => image.to_string(),
+      None => {
+        error!("Please specify an image for {}.", provider.name());
+        process::exit(biopoem_api::PROC_OTHER_ERROR);
+      }
+    };
+    let instance_type = match args
+      .instance_type
+      .as_deref()
+      .or(provider.default_instance_type())
+    {
+      Some(instance_type) => instance_type.to_string(),
+      None => {
+        error!("Please specify an instance type for {}.", provider.name());
+        process::exit(biopoem_api::PROC_OTHER_ERROR);
+      }
+    };
+    let mut data = match deployer::Config::new(
+      provider.as_ref(),
       &args.region,
       &args.zone,
+      &args.cidr,
+      args.auto_ip,
       args.num_of_hosts,
-      &args.image,
-      &args.instance_type,
+      &image,
+      &instance_type,
       "biopoem-secret-key",
-    );
+    ) {
+      Err(msg) => {
+        error!("{}", msg);
+        process::exit(biopoem_api::PROC_OTHER_ERROR);
+      }
+      Ok(data) => data,
+    };
 
     info!("Set the current working directory to {}", &workdir);
     match env::set_current_dir(&workdir) {
@@ -141,6 +181,17 @@ pub async fn run(args: &Arguments) {
       _ => {}
     };
 
+    if args.host_keys {
+      info!("Generate the host keys of instances");
+      match deployer::gen_host_keys(data.hostnames()) {
+        Err(msg) => {
+          error!("{}", msg);
+          process::exit(biopoem_api::PROC_OTHER_ERROR);
+        }
+        Ok(host_keys) => data.set_host_keys(host_keys),
+      }
+    }
+
     info!("Rendering the terraform template to {}", destfile.display());
     match deployer::render_template(&template, &data) {
       Some(result) => {
@@ -150,6 +201,7 @@ pub async fn run(args: &Arguments) {
         if let Some(init_output) = deployer::run(
           "init",
           subdir,
+          provider.as_ref(),
           &args.access_key,
           &args.secret_key,
           &args.region,
@@ -166,6 +218,7 @@ pub async fn run(args: &Arguments) {
         if let Some(apply_output) = deployer::run(
           "apply",
           subdir,
+          provider.as_ref(),
           &args.access_key,
           &args.secret_key,
           &args.region,
@@ -182,6 +235,7 @@ pub async fn run(args: &Arguments) {
         if let Some(outputs) = deployer::run(
           "output",
           subdir,
+          provider.as_ref(),
           &args.access_key,
           &args.secret_key,
           &args.region,
@@ -192,19 +246,38 @@ pub async fn run(args: &Arguments) {
             }
             biopoem_api::Status::Success => {
               let outputs = biopoem_api::vecu8_to_string(&outputs.stdout);
-              let public_ips: Vec<String> = serde_json::from_str(&outputs).unwrap();
+              let instances = match deployer::parse_outputs(&outputs, provider.as_ref(), &data) {
+                Err(msg) => {
+                  error!("{}", msg);
+                  process::exit(biopoem_api::PROC_OTHER_ERROR);
+                }
+                Ok(instances) => instances,
+              };
 
               info!("Generate hosts file");
               match fs::remove_file("hosts") {
                 _ => {}
               };
-              let hosts = deployer::gen_hosts(&data, &public_ips);
+              let username = args.username.as_deref().unwrap_or(provider.username());
+              let hosts = deployer::gen_hosts(&data, &instances, username);
               println!("{:?}, {:?}", hosts, data);
               let mut wtr = csv::Writer::from_writer(fs::File::create("hosts").unwrap());
               for host in hosts {
                 wtr.serialize(host).unwrap();
               }
               wtr.flush().unwrap();
+
+              if args.host_keys {
+                info!("Save the host keys of instances into known_hosts");
+                let known_hosts = deployer::gen_known_hosts(&instances, data.host_keys());
+                match fs::write("known_hosts", known_hosts) {
+                  Err(msg) => {
+                    error!("Cannot write known_hosts, {}", msg);
+                    process::exit(biopoem_api::PROC_OTHER_ERROR);
+                  }
+                  _ => {}
+                };
+              }
             }
           }
         }
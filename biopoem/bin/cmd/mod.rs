@@ -1,18 +1,26 @@
-use std::{fs, process};
+use std::{env, fs, process};
 use std::path::Path;
 use std::path::PathBuf;
+use biopoem_api::server::host::Host;
+use biopoem_api::server::remote::{self, SshOptions};
+use biopoem_api::server::tls;
 use log::LevelFilter;
 use log4rs;
-use log4rs::append::console::ConsoleAppender;
+use log4rs::append::console::{ConsoleAppender, Target};
 use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender, Config, Logger, Root};
 use log4rs::encode::pattern::PatternEncoder;
 use std::error::Error;
+use structopt::StructOpt;
 
 pub mod client;
 pub mod server;
 pub mod deployer;
 pub mod query;
+pub mod collect;
+pub mod stop;
+pub mod samples;
+pub mod validate;
 
 fn notexists_exit(path: &PathBuf, msg: &str) {
   if !Path::exists(path.as_path()) {
@@ -28,11 +36,123 @@ fn exists_exit(path: &PathBuf, msg: &str) {
   }
 }
 
+/// The ssh options of the subcommands which connect hosts.
+#[derive(StructOpt, PartialEq, Debug)]
+pub struct SshArgs {
+  /// The private key file for ssh (such as .ssh/id_rsa). query and stop use it for the tunnels and for killing clients whose API is unreachable.
+  #[structopt(
+    name = "keyfile",
+    short = "k",
+    long = "keyfile",
+    default_value = "keyfile"
+  )]
+  keyfile: String,
+
+  /// How host keys are checked, strict refuses hosts which are not in the known_hosts file, accept-new adds new hosts into it, accept trusts any host key.
+  #[structopt(name = "host-key-policy", long = "host-key-policy", possible_values=&remote::HOST_KEY_POLICIES, default_value = "accept-new")]
+  host_key_policy: String,
+
+  /// The known_hosts file of the run, <workdir>/known_hosts by default (the working directory of the server subcommand). The deployer writes the host keys of new instances into it with --host-keys.
+  #[structopt(name = "known-hosts", long = "known-hosts")]
+  known_hosts: Option<String>,
+
+  /// The ProxyJump of ssh for hosts without the jump_host column, such as user@bastion:22. query and stop reach their clients through ssh tunnels.
+  #[structopt(name = "jump-host", short = "J", long = "jump-host")]
+  jump_host: Option<String>,
+}
+
+/// The known_hosts file is in the working directory by default, so every run has its own host
+/// keys. The ssh_config file is always in the working directory, it is written by write_ssh_config.
+fn ssh_options_with(keyfile: &Path, ssh_args: &SshArgs, workdir: &str) -> SshOptions {
+  let cwd = env::current_dir().unwrap();
+  let known_hosts = match &ssh_args.known_hosts {
+    Some(known_hosts) => cwd.join(known_hosts),
+    None => cwd.join(workdir).join("known_hosts"),
+  };
+  let config_file = cwd.join(workdir).join("ssh_config");
+
+  match SshOptions::new(
+    keyfile,
+    &ssh_args.host_key_policy,
+    &known_hosts,
+    ssh_args.jump_host.as_deref(),
+    &config_file,
+  ) {
+    Err(msg) => {
+      error!("{}", msg);
+      process::exit(biopoem_api::PROC_OTHER_ERROR);
+    }
+    Ok(ssh) => ssh,
+  }
+}
+
+/// The ssh options for subcommands which cannot work without ssh, the keyfile must exist.
+fn ssh_options(ssh_args: &SshArgs, workdir: &str) -> SshOptions {
+  match fs::canonicalize(&ssh_args.keyfile) {
+    Err(msg) => {
+      error!("Cannot find the keyfile {}, {}", &ssh_args.keyfile, msg);
+      process::exit(biopoem_api::PROC_OTHER_ERROR);
+    }
+    Ok(keyfile) => ssh_options_with(&keyfile, ssh_args, workdir),
+  }
+}
+
+/// The ssh options for subcommands which can work without ssh, None if the keyfile doesn't exist.
+/// Hosts behind a jump host need the tunnels, so their number is warned about.
+fn optional_ssh_options(ssh_args: &SshArgs, workdir: &str, hosts: &Vec<Host>) -> Option<SshOptions> {
+  match fs::canonicalize(&ssh_args.keyfile) {
+    Err(msg) => {
+      let jumped = hosts
+        .iter()
+        .filter(|host| remote::jump_host_of(host, ssh_args.jump_host.as_deref()).is_some())
+        .count();
+      if jumped > 0 {
+        warn!(
+          "Cannot find the keyfile {}, {}. {} hosts behind a jump host are reached directly, please use --keyfile for the ssh tunnels.",
+          &ssh_args.keyfile, msg, jumped
+        );
+      }
+      None
+    }
+    Ok(keyfile) => Some(ssh_options_with(&keyfile, ssh_args, workdir)),
+  }
+}
+
+fn write_ssh_config(ssh: &SshOptions, hosts: &Vec<Host>) {
+  if let Err(msg) = ssh.write_config(hosts) {
+    error!("{}", msg);
+    process::exit(biopoem_api::PROC_OTHER_ERROR);
+  }
+}
+
+/// The CA certificate of clients which serve https, None if they serve http.
+fn ca_cert(ca_cert: &Option<String>, tls: bool, workdir: &str) -> Option<reqwest::Certificate> {
+  let filepath = match ca_cert {
+    Some(ca_cert) => PathBuf::from(ca_cert),
+    None if tls => Path::new(workdir).join(tls::CA_CERT),
+    None => return None,
+  };
+
+  match tls::read_ca_cert(&filepath) {
+    Err(msg) => {
+      error!("{}", msg);
+      process::exit(biopoem_api::PROC_OTHER_ERROR);
+    }
+    Ok(ca_cert) => Some(ca_cert),
+  }
+}
+
 fn init_logger(tag_name: &str) -> Result<log4rs::Handle, String> {
+  init_logger_to(tag_name, Target::Stdout)
+}
+
+/// Logs go to stderr when stdout is kept for the machine-readable output.
+fn init_logger_to(tag_name: &str, target: Target) -> Result<log4rs::Handle, String> {
   let stdout = ConsoleAppender::builder()
     .encoder(Box::new(PatternEncoder::new(
       &(format!("[{}]", tag_name) + " {d} - {h({l} - {t} - {m}{n})}"),
     )))
+    .target(target)
     .build();
 
   let config = Config::builder()
@@ -54,16 +174,18 @@ fn init_logger(tag_name: &str) -> Result<log4rs::Handle, String> {
   })
 }
 
-fn init_file_logger(tag_name: &str, logpath: &str) -> Result<log4rs::Handle, String> {
+fn init_file_logger(tag_name: &str, logpath: &str, append: bool) -> Result<log4rs::Handle, String> {
   let stdout = ConsoleAppender::builder()
     .encoder(Box::new(PatternEncoder::new(
       &(format!("[{}]", tag_name) + " {d} - {h({l} - {t} - {m}{n})}"),
     )))
     .build();
 
-  match fs::remove_file(logpath) {
-    _ => {}
-  };
+  if !append {
+    match fs::remove_file(logpath) {
+      _ => {}
+    };
+  }
 
   let file = FileAppender::builder()
     .encoder(Box::new(PatternEncoder::new(
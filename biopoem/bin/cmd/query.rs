@@ -1,9 +1,18 @@
-use super::init_logger;
+use super::init_logger_to;
 use super::notexists_exit;
+use super::{ca_cert, optional_ssh_options, write_ssh_config, SshArgs};
+use biopoem_api::client::model::State;
 use biopoem_api::server;
+use biopoem_api::server::api;
+use biopoem_api::server::tunnel::{self, Endpoint};
+use biopoem_api::server::state::RunState;
 use chrono;
+use futures::stream::{self, StreamExt};
+use log4rs::append::console::Target;
 use prettytable::Table;
 use reqwest;
+use serde::Serialize;
+use std::io::{self, Write};
 use std::path::PathBuf;
 use std::process;
 use structopt::StructOpt;
@@ -17,7 +26,7 @@ pub struct Arguments {
   #[structopt(name = "hosts", short = "-H", long = "hosts", default_value = "hosts")]
   hosts: String,
 
-  /// The monitoring mode.
+  /// The monitoring mode, it stops when no DAG is running and all clients are reachable.
   #[structopt(name = "online", short = "-o", long = "online")]
   online: bool,
 
@@ -29,11 +38,167 @@ pub struct Arguments {
     default_value = "1"
   )]
   interval: u64,
+
+  /// The working directory of the server subcommand, the secret key is read from it.
+  #[structopt(name = "workdir", short = "w", long = "workdir", default_value = ".")]
+  workdir: String,
+
+  /// Secret key, it will override the one in the working directory.
+  #[structopt(name = "secret-key", short = "s", long = "secret-key")]
+  secret_key: Option<String>,
+
+  /// Show the log of the host and follow it like `tail -f`.
+  #[structopt(name = "follow", short = "f", long = "follow")]
+  follow: Option<String>,
+
+  /// Which log to show with --follow.
+  #[structopt(name = "log", short = "l", long = "log", possible_values=&["client", "init"], default_value = "client")]
+  log: String,
+
+  /// How many lines of the log are shown before following.
+  #[structopt(name = "lines", short = "n", long = "lines", default_value = "10")]
+  lines: usize,
+
+  /// How many hosts are queried at the same time.
+  #[structopt(name = "concurrency", short = "c", long = "concurrency", default_value = "20")]
+  concurrency: usize,
+
+  /// The timeout of a request, seconds.
+  #[structopt(name = "timeout", short = "t", long = "timeout", default_value = "10")]
+  timeout: u64,
+
+  /// How many times a request is retried when the client is unreachable.
+  #[structopt(name = "retries", short = "r", long = "retries", default_value = "2")]
+  retries: usize,
+
+  /// The output format, the logs are written to stderr for json and csv.
+  #[structopt(name = "format", short = "F", long = "format", possible_values=&["table", "json", "csv"], default_value = "table")]
+  format: String,
+
+  #[structopt(flatten)]
+  ssh: SshArgs,
+
+  /// Reach the clients through ssh tunnels, it is on if the server subcommand launched them with --local-api.
+  #[structopt(name = "tunnel", long = "tunnel")]
+  tunnel: bool,
+
+  /// The CA certificate for verifying clients which serve https, <workdir>/ca.pem is used if the server subcommand launched them with --tls.
+  #[structopt(name = "ca-cert", long = "ca-cert")]
+  ca_cert: Option<String>,
+}
+
+/// The status of a host in a round of monitoring.
+#[derive(Debug, Serialize)]
+struct Row {
+  current: String,
+  hostname: String,
+  ipaddr: String,
+  status: String,
+  progress: String,
+  /// None if the client cannot be queried.
+  #[serde(skip)]
+  state: Option<State>,
+}
+
+/// The exit code of the aggregate state, failed DAGs take precedence over unreachable clients,
+/// and unreachable clients over running DAGs.
+fn exit_code(rows: &Vec<Row>) -> i32 {
+  if rows
+    .iter()
+    .any(|row| row.state == Some(State::Failed) || row.state == Some(State::Cancelled))
+  {
+    biopoem_api::PROC_DAG_FAILED
+  } else if rows.iter().any(|row| row.state.is_none()) {
+    biopoem_api::PROC_UNREACHABLE
+  } else if rows
+    .iter()
+    .any(|row| row.state == Some(State::Running) || row.state == Some(State::Waiting))
+  {
+    biopoem_api::PROC_DAG_RUNNING
+  } else {
+    biopoem_api::PROC_SUCCESS
+  }
+}
+
+fn print_rows(rows: &Vec<Row>, format: &str) {
+  match format {
+    "json" => println!("{}", serde_json::to_string(rows).unwrap()),
+    "csv" => {
+      let mut wtr = csv::Writer::from_writer(io::stdout());
+      for row in rows {
+        if let Err(msg) = wtr.serialize(row) {
+          error!("Cannot write the csv, {}", msg);
+        }
+      }
+      let _ = wtr.flush();
+    }
+    _ => {
+      let mut table = Table::new();
+      table.add_row(row![
+        "current",
+        "hostname",
+        "status",
+        "progress"
+      ]);
+      for row in rows {
+        table.add_row(row![
+          row.current,
+          row.hostname,
+          row.status,
+          row.progress
+        ]);
+      }
+      table.printstd();
+    }
+  }
+}
+
+/// Print the log as it grows, reconnect from the last offset when the stream is broken.
+/// Returns the exit code when the secret key is rejected.
+async fn follow_log(
+  client: &reqwest::Client,
+  base_url: &str,
+  secret_key: &str,
+  name: &str,
+  lines: usize,
+) -> i32 {
+  let mut offset: Option<u64> = None;
+  loop {
+    let result = api::follow_log(
+      client,
+      base_url,
+      secret_key,
+      name,
+      offset,
+      lines,
+      |chunk| {
+        print!("{}", chunk.data);
+        let _ = io::stdout().flush();
+        offset = Some(chunk.offset);
+      },
+    )
+    .await;
+
+    match result {
+      Err(api::ApiError::Unauthorized) => {
+        error!(target:"stdout", "{}", api::ApiError::Unauthorized);
+        return biopoem_api::PROC_OTHER_ERROR;
+      }
+      Err(err) => warn!("{} {}, reconnect later.", err, err.detail()),
+      Ok(_) => warn!("The log stream is closed, reconnect later."),
+    };
+
+    time::sleep(time::Duration::from_secs(3)).await;
+  }
 }
 
 #[tokio::main]
 pub async fn run(args: &Arguments) {
-  if let Err(log) = init_logger("Query") {
+  let target = match &args.format[..] {
+    "table" => Target::Stdout,
+    _ => Target::Stderr,
+  };
+  if let Err(log) = init_logger_to("Query", target) {
     error!(target:"stdout", "Log initialization error, {}", log);
     process::exit(biopoem_api::PROC_OTHER_ERROR);
   };
@@ -43,57 +208,174 @@ pub async fn run(args: &Arguments) {
     &format!("No such file: {} file doesn't exist.", &args.hosts),
   );
 
+  let state = RunState::read(&args.workdir);
+  let secret_key = match (&args.secret_key, &state) {
+    (Some(secret_key), _) => secret_key.to_string(),
+    (None, Ok(state)) => state.secret_key().to_string(),
+    (None, Err(msg)) => {
+      error!("{}, please specify the working directory of the server subcommand or the secret key.", msg);
+      process::exit(biopoem_api::PROC_OTHER_ERROR);
+    }
+  };
+  let mut state = state.ok();
+  let any_tls = state.as_ref().map_or(false, |state| state.any_tls());
+  let ca_cert = ca_cert(&args.ca_cert, any_tls, &args.workdir);
+  let with_ca = |mut builder: reqwest::ClientBuilder| {
+    if let Some(ca_cert) = &ca_cert {
+      builder = builder.add_root_certificate(ca_cert.clone());
+    }
+    builder.build().unwrap()
+  };
+
   let hosts = server::host::read_hosts(&args.hosts);
+  let local_apis: Vec<bool> = hosts
+    .iter()
+    .map(|host| args.tunnel || state.as_ref().map_or(false, |state| state.local_api(host.hostname())))
+    .collect();
+  // Clients serve https if they were launched with --tls, or all of them with --ca-cert.
+  let tls_flags: Vec<bool> = hosts
+    .iter()
+    .map(|host| args.ca_cert.is_some() || state.as_ref().map_or(false, |state| state.tls(host.hostname())))
+    .collect();
+  // Without a keyfile, hosts behind a jump host are queried directly.
+  let ssh = optional_ssh_options(&args.ssh, &args.workdir, &hosts);
+  if let Some(ssh) = &ssh {
+    write_ssh_config(ssh, &hosts);
+  }
+  let ssh = ssh.as_ref();
+
+  if let Some(hostname) = &args.follow {
+    match hosts.iter().position(|host| host.hostname() == hostname) {
+      Some(idx) => match tunnel::api_endpoint(&hosts[idx], ssh, local_apis[idx]).await {
+        Err(msg) => {
+          error!(target:"stdout", "{}", msg);
+          process::exit(biopoem_api::PROC_UNREACHABLE);
+        }
+        Ok(endpoint) => {
+          // The log stream stays open, so there is no timeout.
+          let client = with_ca(reqwest::Client::builder());
+          let base_url = api::base_url(&endpoint.ipaddr, endpoint.port, tls_flags[idx]);
+          let code = follow_log(&client, &base_url, &secret_key, &args.log, args.lines).await;
+          // process::exit doesn't run destructors, the tunnel is closed first.
+          drop(endpoint);
+          process::exit(code);
+        }
+      },
+      None => {
+        error!(target:"stdout", "Not found {} in {}.", hostname, &args.hosts);
+        process::exit(biopoem_api::PROC_OTHER_ERROR);
+      }
+    }
+  }
+
+  // One unreachable host must not stall the others for the TCP timeout of the OS.
+  let client = with_ca(reqwest::Client::builder().timeout(time::Duration::from_secs(args.timeout)));
+  let retry = api::Retry {
+    retries: args.retries,
+    delay: time::Duration::from_secs(1),
+  };
+  // The tunnels are kept open until query exits.
+  let mut endpoints: Vec<Result<Endpoint, String>> = stream::iter(hosts.iter().zip(local_apis.iter()))
+    .map(|(host, local_api)| tunnel::api_endpoint(host, ssh, *local_api))
+    .buffered(args.concurrency.max(1))
+    .collect()
+    .await;
+
   let unit = 60 * args.interval;
   let mut num = 1;
   // Get logs periodically
   loop {
-    println!("\n*** Monitoring at {} minutes ****\n", num * unit / 60);
-
-    let mut table = Table::new();
-    table.add_row(row![
-      "current",
-      "hostname",
-      "status",
-      "client_log",
-      "init_log"
-    ]);
-
-    for host in &hosts {
+    if args.format == "table" {
+      println!("\n*** Monitoring at {} minutes ****\n", num * unit / 60);
+    }
+
+    let client = &client;
+    let (secret_key, tls_flags) = (&secret_key, &tls_flags);
+    let results: Vec<_> = stream::iter(hosts.iter().zip(endpoints.iter()).enumerate())
+      .map(|(idx, (host, endpoint))| async move {
+        let result = match endpoint {
+          Err(msg) => Err(api::ApiError::Tunnel(msg.clone())),
+          Ok(endpoint) => {
+            let base_url = api::base_url(&endpoint.ipaddr, endpoint.port, tls_flags[idx]);
+            api::fetch_status_with_retry(client, &base_url, secret_key, retry).await
+          }
+        };
+        (host, result, chrono::Local::now())
+      })
+      .buffered(args.concurrency.max(1))
+      .collect()
+      .await;
+
+    let mut rows: Vec<Row> = vec![];
+    // The tunnels to reopen before the next round, ssh may have exited.
+    let mut broken: Vec<usize> = vec![];
+    for (idx, (host, result, current)) in results.into_iter().enumerate() {
+      let tunneled = match &endpoints[idx] {
+        Err(_) => true,
+        Ok(endpoint) => endpoint.is_tunneled(),
+      };
+      match &result {
+        Err(err) if err.is_unreachable() && tunneled => broken.push(idx),
+        _ => {}
+      };
+
       let hostname = host.hostname().to_string();
       let ipaddr = host.ipaddr().to_string();
-      let status_url = format!(
-        "http://{}:{}/status?secret_key=biopoem-N8kOaPq6",
-        ipaddr, 3000
-      );
-
-      let status = match reqwest::get(status_url).await {
-        Err(msg) => "Connection Failed".to_string(),
-        Ok(response) => response.text().await.unwrap_or("Running".to_string()),
+      let (status, progress, run_state) = match result {
+        Err(err) => (err.to_string(), err.detail().to_string(), None),
+        Ok(run_status) => (
+          run_status.state.to_string(),
+          run_status.summary(),
+          Some(run_status.state),
+        ),
       };
 
-      let client_log_url = format!(
-        "http://{}:{}/log/client?secret_key=biopoem-N8kOaPq6",
-        ipaddr, 3000
-      );
+      // Keep the last known status of hosts, server subcommand relaunches failed ones.
+      if let Some(state) = &mut state {
+        state.record_status(&hostname, &status);
+      }
 
-      let init_log_url = format!(
-        "http://{}:{}/log/init?secret_key=biopoem-N8kOaPq6",
-        ipaddr, 3000
-      );
-
-      let now = chrono::Local::now().format("%Y-%m-%d][%H:%M:%S");
-      table.add_row(row![now, hostname, status, client_log_url, init_log_url]);
+      rows.push(Row {
+        current: current.format("%Y-%m-%d][%H:%M:%S").to_string(),
+        hostname: hostname,
+        ipaddr: ipaddr,
+        status: status,
+        progress: progress,
+        state: run_state,
+      });
     }
 
-    table.printstd();
+    print_rows(&rows, &args.format);
     num += 1;
 
-    // Run query once.
-    if !args.online {
-      break;
-    } else {
-      time::sleep(time::Duration::from_secs(unit)).await;
+    if let Some(state) = &state {
+      if let Err(msg) = state.write(&args.workdir) {
+        warn!("{}", msg);
+      }
+    }
+
+    // Run query once, or until all DAGs finish.
+    let code = exit_code(&rows);
+    let finished = rows.iter().all(|row| match row.state {
+      None | Some(State::Running) | Some(State::Waiting) => false,
+      _ => true,
+    });
+    if !args.online || finished {
+      // process::exit doesn't run destructors, the tunnels are closed first.
+      drop(endpoints);
+      process::exit(code);
     }
+
+    let (hosts, local_apis) = (&hosts, &local_apis);
+    let reopened: Vec<(usize, Result<Endpoint, String>)> = stream::iter(broken)
+      .map(|idx| async move { (idx, tunnel::api_endpoint(&hosts[idx], ssh, local_apis[idx]).await) })
+      .buffered(args.concurrency.max(1))
+      .collect()
+      .await;
+    for (idx, endpoint) in reopened {
+      endpoints[idx] = endpoint;
+    }
+
+    time::sleep(time::Duration::from_secs(unit)).await;
   }
 }
@@ -0,0 +1,160 @@
+use super::{exists_exit, init_logger, notexists_exit};
+use biopoem_api::server::samples::{self, Strategy};
+use biopoem_api::server::{self, dag};
+use prettytable::Table;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::{fs, process};
+use structopt::StructOpt;
+
+/// Generate the variable file from a sample sheet for Biopoem
+#[derive(StructOpt, PartialEq, Debug)]
+#[structopt(setting=structopt::clap::AppSettings::ColoredHelp, name="Biopoem - Samples", author="Jingcheng Yang <yjcyxky@163.com>")]
+pub struct Arguments {
+  /// The sample sheet (csv, or tsv with the tsv/txt extension), it must have a sample_id column.
+  #[structopt(name = "sample-sheet", short = "s", long = "sample-sheet")]
+  sample_sheet: String,
+
+  /// The host file.
+  #[structopt(name = "hosts", short = "-H", long = "hosts", default_value = "hosts")]
+  hosts: String,
+
+  /// How samples are assigned to hosts, balanced is by the total size of the files in --size-columns.
+  #[structopt(name = "strategy", short = "S", long = "strategy", possible_values=&samples::STRATEGIES, default_value = "round-robin")]
+  strategy: String,
+
+  /// How many samples every host takes with the per-host strategy.
+  #[structopt(name = "per-host", short = "n", long = "per-host", default_value = "1")]
+  per_host: usize,
+
+  /// The columns of files whose sizes are used by the balanced strategy.
+  #[structopt(name = "size-columns", long = "size-columns", default_value = "fastq_1,fastq_2", use_delimiter = true)]
+  size_columns: Vec<String>,
+
+  /// The variable file which the samples are added into, such as one with the _global section.
+  #[structopt(name = "base", short = "b", long = "base")]
+  base: Option<String>,
+
+  /// The format of the base file, it is detected by the extension (json without extension) by default.
+  #[structopt(name = "base-format", long = "base-format", possible_values=&dag::FORMATS)]
+  base_format: Option<String>,
+
+  /// The variable file for DAG (json).
+  #[structopt(name = "output", short = "o", long = "output", default_value = "variables")]
+  output: String,
+
+  /// Overwrite the output file if it exists.
+  #[structopt(name = "force", short = "f", long = "force")]
+  force: bool,
+}
+
+pub fn run(args: &Arguments) {
+  if let Err(log) = init_logger("Samples") {
+    error!(target:"stdout", "Log initialization error, {}", log);
+    process::exit(biopoem_api::PROC_OTHER_ERROR);
+  };
+
+  notexists_exit(
+    &PathBuf::from(&args.hosts),
+    &format!("No such file: {} file doesn't exist.", &args.hosts),
+  );
+  if !args.force {
+    exists_exit(
+      &PathBuf::from(&args.output),
+      &format!("{} exists, please use --force to overwrite it.", &args.output),
+    );
+  }
+
+  let sample_sheet = match samples::read_sample_sheet(&PathBuf::from(&args.sample_sheet)) {
+    Err(msg) => {
+      error!("{}", msg);
+      process::exit(biopoem_api::PROC_PARSE_ERROR);
+    }
+    Ok(sample_sheet) => sample_sheet,
+  };
+
+  let strategy = match &args.strategy[..] {
+    "balanced" => Strategy::Balanced,
+    "per-host" => Strategy::PerHost(args.per_host),
+    _ => Strategy::RoundRobin,
+  };
+
+  let mut sizes: Vec<u64> = vec![];
+  if strategy == Strategy::Balanced {
+    for sample in &sample_sheet {
+      match samples::sample_size(sample, &args.size_columns) {
+        Err(msg) => {
+          error!("{}", msg);
+          process::exit(biopoem_api::PROC_OTHER_ERROR);
+        }
+        Ok(size) => sizes.push(size),
+      }
+    }
+  }
+
+  let hostnames: Vec<String> = server::host::read_hosts(&args.hosts)
+    .iter()
+    .map(|host| host.hostname().to_string())
+    .collect();
+  let assignment = match samples::assign(sample_sheet.len(), hostnames.len(), strategy, &sizes) {
+    Err(msg) => {
+      error!("{}", msg);
+      process::exit(biopoem_api::PROC_OTHER_ERROR);
+    }
+    Ok(assignment) => assignment,
+  };
+
+  let base = match &args.base {
+    Some(base) => {
+      let base = PathBuf::from(base);
+      let format = match &args.base_format {
+        Some(format) => format.as_str(),
+        None => match dag::detect_format(&base) {
+          Err(msg) => {
+            error!("{}, please use --base-format.", msg);
+            process::exit(biopoem_api::PROC_PARSE_ERROR);
+          }
+          Ok(format) => format,
+        },
+      };
+      match dag::read_variables(&base, format) {
+        Err(msg) => {
+          error!("{}", msg);
+          process::exit(biopoem_api::PROC_PARSE_ERROR);
+        }
+        Ok(base) => base,
+      }
+    }
+    None => Value::Null,
+  };
+  let variables = samples::gen_variables(base, &hostnames, &sample_sheet, &assignment);
+  match fs::write(&args.output, serde_json::to_string_pretty(&variables).unwrap()) {
+    Err(msg) => {
+      error!("Cannot write {}, {}", &args.output, msg);
+      process::exit(biopoem_api::PROC_OTHER_ERROR);
+    }
+    _ => {}
+  };
+
+  let mut table = Table::new();
+  table.add_row(row!["hostname", "num_of_samples", "samples"]);
+  for (hostname, indexes) in hostnames.iter().zip(assignment.iter()) {
+    let sample_ids: Vec<&str> = indexes
+      .iter()
+      .map(|idx| sample_sheet[*idx]["sample_id"].as_str())
+      .collect();
+    table.add_row(row![hostname, indexes.len(), sample_ids.join(", ")]);
+  }
+  table.printstd();
+
+  let idle = assignment.iter().filter(|indexes| indexes.is_empty()).count();
+  if idle > 0 {
+    info!("{} hosts have no samples, their samples lists are empty.", idle);
+  }
+  info!(
+    "Assign {} samples to {} hosts, save the variables to {}.",
+    sample_sheet.len(),
+    hostnames.len() - idle,
+    &args.output
+  );
+}
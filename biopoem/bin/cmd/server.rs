@@ -1,9 +1,19 @@
-use biopoem_api::{server, server::dag, server::remote};
+use biopoem_api::{server, server::dag, server::host::Host, server::remote, server::remote::Stage};
+use biopoem_api::server::tls::{self, Ca};
+use biopoem_api::server::tunnel;
+use biopoem_api::server::api::{self, ApiError};
+use biopoem_api::client::model::State;
+use biopoem_api::server::remote::{Binary, SshOptions, Supervision, Supervisor, TlsFiles};
+use biopoem_api::server::state::RunState;
+use futures::stream::{self, StreamExt};
+use prettytable::Table;
+use serde_json::Value;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
 use std::{env, fs, process};
 use structopt::StructOpt;
-use super::init_logger;
+use super::{init_logger, ssh_options, write_ssh_config, SshArgs};
 
 /// Server for Biopoem
 #[derive(StructOpt, PartialEq, Debug)]
@@ -26,7 +36,7 @@ pub struct Arguments {
   )]
   dag_template: String,
 
-  /// The variable file for DAG (json).
+  /// The variable file for DAG (json, yaml, toml, csv or tsv).
   #[structopt(
     name = "variable-file",
     short = "f",
@@ -35,14 +45,12 @@ pub struct Arguments {
   )]
   variable_file: String,
 
-  /// The private key file for ssh (such as .ssh/id_rsa).
-  #[structopt(
-    name = "keyfile",
-    short = "k",
-    long = "keyfile",
-    default_value = "keyfile"
-  )]
-  keyfile: String,
+  /// The format of the variable file, it is detected by the extension (json without extension) by default.
+  #[structopt(name = "variables-format", long = "variables-format", possible_values=&dag::FORMATS)]
+  variables_format: Option<String>,
+
+  #[structopt(flatten)]
+  ssh: SshArgs,
 
   /// The working directory on remote machine.
   #[structopt(
@@ -52,6 +60,448 @@ pub struct Arguments {
     default_value = "/mnt/biopoem"
   )]
   remote_workdir: String,
+
+  /// How many hosts are deployed at the same time.
+  #[structopt(
+    name = "concurrency",
+    short = "c",
+    long = "concurrency",
+    default_value = "10"
+  )]
+  concurrency: usize,
+
+  /// The biopoem binary which is uploaded to remote machines, the running one by default.
+  #[structopt(name = "client-binary", short = "b", long = "client-binary")]
+  client_binary: Option<String>,
+
+  /// Download the biopoem binary from the url on remote machines instead of uploading it.
+  #[structopt(name = "biopoem-bin-url", short = "u", long = "biopoem-bin-url")]
+  biopoem_bin_url: Option<String>,
+
+  /// Deploy the hosts only, comma-separated hostnames, such as biopoem001,biopoem003.
+  #[structopt(name = "hosts-filter", short = "F", long = "hosts-filter")]
+  hosts_filter: Option<String>,
+
+  /// Redeploy the hosts whose DAG failed or whose client is unreachable only.
+  #[structopt(name = "only-failed", short = "O", long = "only-failed")]
+  only_failed: bool,
+
+  /// What keeps the client running, systemd-run for a transient unit, systemd for a unit which is started after reboot. Hosts without systemd fall back to nohup.
+  #[structopt(name = "supervisor", short = "S", long = "supervisor", possible_values=&remote::SUPERVISORS, default_value = "nohup")]
+  supervisor: Supervisor,
+
+  /// The restart policy of the systemd unit.
+  #[structopt(name = "restart", long = "restart", possible_values=&remote::RESTART_POLICIES, default_value = "on-failure")]
+  restart: String,
+
+  /// Environment variable of the client, such as PATH=/opt/conda/bin:/usr/bin, can be given multiple times.
+  #[structopt(name = "env", short = "e", long = "env")]
+  env: Vec<String>,
+
+  /// The client listens on 127.0.0.1 only, query, stop and server subcommands reach its API through ssh tunnels, so the port needn't be open.
+  #[structopt(name = "local-api", long = "local-api")]
+  local_api: bool,
+
+  /// The client serves https with a certificate signed by the CA of the run (ca.pem in the working directory), query and stop verify clients against it.
+  #[structopt(name = "tls", long = "tls")]
+  tls: bool,
+
+  /// Run all tasks again and truncate the logs, the clients skip the tasks which succeeded in the previous launch by default. The client is launched with --fresh, so it starts fresh again when it is restarted by systemd.
+  #[structopt(name = "fresh", long = "fresh")]
+  fresh: bool,
+
+  /// Render the DAGs and save them with the commands which would be run on every host into results/<hostname>, no host is connected. It can't be used with --only-failed, which queries the clients.
+  #[structopt(name = "dry-run", short = "n", long = "dry-run", conflicts_with = "only-failed")]
+  dry_run: bool,
+}
+
+/// What happened to a host during deployment.
+struct Outcome {
+  index: usize,
+  hostname: String,
+  ipaddr: String,
+  stage: Stage,
+  supervisor: Option<Supervisor>,
+  error: Option<String>,
+}
+
+async fn upload_and_launch(
+  session: &openssh::Session,
+  remote_workdir: &str,
+  username: &str,
+  destfile: &PathBuf,
+  binary: &Binary,
+  api_port: u16,
+  secret_key: &str,
+  supervision: &Supervision,
+  tls: Option<&TlsFiles>,
+  stage: &mut Stage,
+) -> Result<Supervisor, String> {
+  remote::init_env(session, remote_workdir, destfile, binary, tls).await?;
+  *stage = Stage::Uploaded;
+
+  // A client left by the previous launch holds the port.
+  remote::kill_biopoem(session, remote_workdir).await?;
+
+  let supervisor =
+    remote::launch_biopoem(session, remote_workdir, username, "", api_port, secret_key, supervision).await?;
+  *stage = Stage::Launched;
+
+  Ok(supervisor)
+}
+
+async fn launch(
+  host: &Host,
+  dag: &str,
+  ssh: &SshOptions,
+  remote_workdir: &str,
+  binary: &Binary,
+  secret_key: &str,
+  supervision: &Supervision,
+  ca: Option<&Ca>,
+  stage: &mut Stage,
+) -> Result<Supervisor, String> {
+  // Save dag file.
+  let hostname = host.hostname();
+  let subdir = format!("results/{}", hostname);
+  biopoem_api::makedir(&subdir);
+
+  let destfile = Path::new(&subdir).join("dag.factfile");
+  info!("Save the dag to {}", destfile.display());
+  fs::write(&destfile, dag)
+    .map_err(|msg| format!("Cannot write {}, {}", destfile.display(), msg))?;
+
+  let tls_files = match ca {
+    Some(ca) => {
+      let (cert, key) = ca.issue(host)?;
+      let files = TlsFiles {
+        cert: Path::new(&subdir).join("client.pem"),
+        key: Path::new(&subdir).join("client.key"),
+      };
+      fs::write(&files.cert, cert)
+        .map_err(|msg| format!("Cannot write {}, {}", files.cert.display(), msg))?;
+      tls::write_private(&files.key, &key)?;
+      Some(files)
+    }
+    None => None,
+  };
+  *stage = Stage::Rendered;
+
+  // Initialize (Upload biopoem and dag file.)
+  let port = host
+    .port()
+    .parse::<u16>()
+    .map_err(|msg| format!("Invalid port {}, {}", host.port(), msg))?;
+  let session = remote::init_session(host.ipaddr(), port, host.username(), ssh)
+    .await
+    .map_err(|msg| format!("Cannot connect {}, {}", host.ipaddr(), msg))?;
+  *stage = Stage::Connected;
+
+  let result = upload_and_launch(
+    &session,
+    remote_workdir,
+    host.username(),
+    &destfile,
+    binary,
+    host.api_port(),
+    secret_key,
+    supervision,
+    tls_files.as_ref(),
+    stage,
+  )
+  .await;
+  match session.close().await {
+    Err(msg) => warn!("{}", msg),
+    _ => {}
+  };
+
+  result
+}
+
+async fn deploy(
+  index: usize,
+  host: &Host,
+  dag: &str,
+  ssh: &SshOptions,
+  remote_workdir: &str,
+  binary: &Binary,
+  secret_key: &str,
+  supervision: &Supervision,
+  ca: Option<&Ca>,
+) -> Outcome {
+  let mut stage = Stage::Pending;
+  let mut supervisor = None;
+  let error = match launch(
+    host,
+    dag,
+    ssh,
+    remote_workdir,
+    binary,
+    secret_key,
+    supervision,
+    ca,
+    &mut stage,
+  )
+  .await
+  {
+    Err(msg) => {
+      error!("{}: {}", host.hostname(), msg);
+      Some(msg)
+    }
+    Ok(used) => {
+      info!("{}: biopoem client is launched with {}.", host.hostname(), used);
+      supervisor = Some(used);
+      None
+    }
+  };
+
+  Outcome {
+    index: index,
+    hostname: host.hostname().to_string(),
+    ipaddr: host.ipaddr().to_string(),
+    stage: stage,
+    supervisor: supervisor,
+    error: error,
+  }
+}
+
+/// Render and check the DAGs of all hosts.
+fn render_dags(
+  hosts: &Vec<Host>,
+  all_hostnames: &Vec<String>,
+  template: &str,
+  variables: &Value,
+) -> Vec<Result<String, Vec<String>>> {
+  hosts
+    .iter()
+    .enumerate()
+    .map(|(index, host)| {
+      // host_index and num_hosts of templates are about the hosts file, no matter which hosts are deployed.
+      let host_index = all_hostnames
+        .iter()
+        .position(|hostname| hostname == host.hostname())
+        .unwrap_or(index);
+      dag::render_and_validate(template, variables, host, host_index, all_hostnames.len())
+    })
+    .collect()
+}
+
+/// Nothing is deployed if some DAGs are invalid, all errors are reported before connecting any host.
+/// The hosts with valid DAGs are listed for --hosts-filter, so that they can be deployed alone.
+fn check_dags(hosts: &Vec<Host>, results: Vec<Result<String, Vec<String>>>) -> Vec<String> {
+  let mut dags: Vec<String> = vec![];
+  let mut valid: Vec<&str> = vec![];
+  let mut table = Table::new();
+  table.add_row(row!["hostname", "errors"]);
+  for (host, result) in hosts.iter().zip(results.into_iter()) {
+    match result {
+      Ok(content) => {
+        dags.push(content);
+        valid.push(host.hostname());
+      }
+      Err(errors) => {
+        table.add_row(row![host.hostname(), errors.join("\n")]);
+      }
+    }
+  }
+
+  if valid.len() < hosts.len() {
+    table.printstd();
+    error!(
+      "{} of {} DAGs are invalid, nothing is deployed.",
+      hosts.len() - valid.len(),
+      hosts.len()
+    );
+    if !valid.is_empty() {
+      error!(
+        "Fix the DAGs, or deploy the hosts with valid DAGs only by --hosts-filter {}",
+        valid.join(",")
+      );
+    }
+    process::exit(biopoem_api::PROC_PARSE_ERROR);
+  }
+
+  dags
+}
+
+/// Save the DAGs and the commands which would be run into results/<hostname> without
+/// connecting any host.
+fn dry_run(
+  hosts: &Vec<Host>,
+  results: &Vec<Result<String, Vec<String>>>,
+  variables: &Value,
+  remote_workdir: &str,
+  binary: &Binary,
+  supervision: &Supervision,
+) {
+  let mut table = Table::new();
+  table.add_row(row!["hostname", "ipaddr", "variables", "dag", "commands"]);
+  let mut missing: Vec<&str> = vec![];
+  let mut num_invalid = 0;
+  for (host, result) in hosts.iter().zip(results.iter()) {
+    let hostname = host.hostname();
+    let subdir = Path::new("results").join(hostname);
+    biopoem_api::makedir(&subdir.to_string_lossy());
+
+    let in_variables = match variables.get(hostname) {
+      Some(_) => "Found",
+      None => {
+        missing.push(hostname);
+        "Missing"
+      }
+    };
+
+    let destfile = subdir.join("dag.factfile");
+    let dag = match result {
+      Ok(content) => match fs::write(&destfile, content) {
+        Err(msg) => format!("Cannot write {}, {}", destfile.display(), msg),
+        Ok(_) => "Valid".to_string(),
+      },
+      Err(errors) => {
+        num_invalid += 1;
+        format!("Invalid\n{}", errors.join("\n"))
+      }
+    };
+
+    let steps = remote::plan(
+      remote_workdir,
+      host.username(),
+      &destfile,
+      binary,
+      "",
+      host.api_port(),
+      // The commands files are left in the working directory, the secret key is kept out of them.
+      "<secret_key>",
+      supervision,
+    );
+    let cmdfile = subdir.join("commands.sh");
+    let commands = match fs::write(
+      &cmdfile,
+      format!(
+        "# ssh -p {} {}@{}\n{}\n",
+        host.port(),
+        host.username(),
+        host.ipaddr(),
+        steps.join("\n")
+      ),
+    ) {
+      Err(msg) => format!("Cannot write {}, {}", cmdfile.display(), msg),
+      Ok(_) => cmdfile.display().to_string(),
+    };
+
+    table.add_row(row![hostname, host.ipaddr(), in_variables, dag, commands]);
+  }
+
+  table.printstd();
+  if !missing.is_empty() {
+    warn!(
+      "{} hosts are missing from the variable file: {}",
+      missing.len(),
+      missing.join(", ")
+    );
+  }
+  if num_invalid > 0 {
+    error!("{} of {} DAGs are invalid.", num_invalid, hosts.len());
+    process::exit(biopoem_api::PROC_PARSE_ERROR);
+  }
+}
+
+/// Keep the hosts whose DAG failed or whose client is unreachable, the others are reported.
+async fn select_failed(
+  hosts: Vec<Host>,
+  secret_key: &str,
+  ssh: &SshOptions,
+  state: &mut RunState,
+) -> Vec<Host> {
+  // The clients of the previous launch serving https are verified against the CA, without it
+  // they would all look unreachable and be relaunched.
+  let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(10));
+  if state.any_tls() {
+    match tls::read_ca_cert(Path::new(tls::CA_CERT)) {
+      Err(msg) => {
+        error!("{}", msg);
+        process::exit(biopoem_api::PROC_OTHER_ERROR);
+      }
+      Ok(ca_cert) => builder = builder.add_root_certificate(ca_cert),
+    }
+  }
+  let client = builder.build().unwrap();
+
+  let (client, previous) = (&client, &*state);
+  let checks: Vec<(Host, Result<State, ApiError>)> = stream::iter(hosts.into_iter())
+    .map(|host| async move {
+      // How the client of the previous launch listens.
+      let (local_api, tls) = (previous.local_api(host.hostname()), previous.tls(host.hostname()));
+      let result = match tunnel::api_endpoint(&host, Some(ssh), local_api).await {
+        Err(msg) => Err(ApiError::Tunnel(msg)),
+        Ok(endpoint) => {
+          let base_url = api::base_url(&endpoint.ipaddr, endpoint.port, tls);
+          api::fetch_status(client, &base_url, secret_key)
+            .await
+            .map(|status| status.state)
+        }
+      };
+      (host, result)
+    })
+    .buffered(10)
+    .collect()
+    .await;
+
+  let mut failed_hosts: Vec<Host> = vec![];
+  for (host, result) in checks {
+    let selected = match &result {
+      Ok(state) => {
+        info!("{}: the DAG is {}.", host.hostname(), state);
+        *state == State::Failed
+      }
+      Err(err) if err.is_unreachable() => {
+        info!("{}: the client is unreachable, {} {}", host.hostname(), err, err.detail());
+        true
+      }
+      Err(err) => {
+        warn!("{}: {} {}, skip it.", host.hostname(), err, err.detail());
+        false
+      }
+    };
+
+    let status = match &result {
+      Ok(state) => state.to_string(),
+      Err(err) => err.to_string(),
+    };
+    state.record_status(host.hostname(), &status);
+
+    if selected {
+      failed_hosts.push(host);
+    }
+  }
+
+  failed_hosts
+}
+
+fn print_summary(outcomes: &Vec<Outcome>) {
+  let mut table = Table::new();
+  table.add_row(row!["hostname", "ipaddr", "stage", "supervisor", "result", "reason"]);
+
+  for outcome in outcomes {
+    let (result, reason) = match &outcome.error {
+      Some(msg) => ("Failed", msg.as_str()),
+      None => ("Success", ""),
+    };
+    let supervisor = match outcome.supervisor {
+      Some(supervisor) => supervisor.to_string(),
+      None => "".to_string(),
+    };
+    table.add_row(row![
+      outcome.hostname,
+      outcome.ipaddr,
+      outcome.stage,
+      supervisor,
+      result,
+      reason
+    ]);
+  }
+
+  table.printstd();
 }
 
 #[tokio::main]
@@ -63,16 +513,74 @@ pub async fn run(args: &Arguments) {
   let dag_template = fs::canonicalize(tmplpath).unwrap();
 
   let varpath = PathBuf::from(&args.variable_file);
-  let variable_file = fs::canonicalize(varpath).unwrap();
+  let format = match &args.variables_format {
+    Some(format) => format.as_str(),
+    None => match dag::detect_format(&varpath) {
+      Err(msg) => {
+        println!("{}, please use --variables-format.", msg);
+        process::exit(biopoem_api::PROC_PARSE_ERROR);
+      }
+      Ok(format) => format,
+    },
+  };
+  let variables = match dag::read_variables(&varpath, format) {
+    Err(msg) => {
+      println!("{}", msg);
+      process::exit(biopoem_api::PROC_PARSE_ERROR);
+    }
+    Ok(variables) => variables,
+  };
+
+  let binary = match &args.biopoem_bin_url {
+    Some(biopoem_bin_url) => Binary::Url(biopoem_bin_url.to_string()),
+    None => {
+      let binpath = match &args.client_binary {
+        Some(client_binary) => fs::canonicalize(client_binary).unwrap(),
+        None => env::current_exe().unwrap(),
+      };
+
+      match biopoem_api::sha256sum(&binpath) {
+        Err(msg) => {
+          println!("{}", msg);
+          process::exit(biopoem_api::PROC_OTHER_ERROR);
+        }
+        Ok(checksum) => Binary::Local {
+          path: binpath,
+          checksum: checksum,
+        },
+      }
+    }
+  };
 
-  let keypath = PathBuf::from(&args.keyfile);
-  let keyfile = fs::canonicalize(keypath).unwrap();
+  let mut envs: Vec<(String, String)> = vec![];
+  for env in &args.env {
+    match env.split_once('=') {
+      Some((key, value)) if key != "" => envs.push((key.to_string(), value.to_string())),
+      _ => {
+        println!("Invalid environment variable {}, must be KEY=VALUE.", env);
+        process::exit(biopoem_api::PROC_PARSE_ERROR);
+      }
+    }
+  }
+  let supervision = Supervision {
+    supervisor: args.supervisor,
+    restart: args.restart.clone(),
+    envs: envs,
+    api_host: match args.local_api {
+      true => "127.0.0.1".to_string(),
+      false => "0.0.0.0".to_string(),
+    },
+    tls: args.tls,
+    fresh: args.fresh,
+  };
 
   if let Err(log) = init_logger("Server") {
     error!(target:"stdout", "Log initialization error, {}", log);
     process::exit(biopoem_api::PROC_OTHER_ERROR);
   };
 
+  let ssh = ssh_options(&args.ssh, workdir);
+
   info!("Set the current working directory to {}", &workdir);
   match env::set_current_dir(&workdir) {
     Err(msg) => {
@@ -82,42 +590,95 @@ pub async fn run(args: &Arguments) {
     _ => {}
   };
 
-  let hosts = server::host::read_hosts(&args.hosts);
-  for host in &hosts {
-    // Generate dag file.
-    let hostname = host.hostname();
-    let subdir = format!("results/{}", hostname);
-    biopoem_api::makedir(&subdir);
-
-    let destfile = Path::new(&subdir).join("dag.factfile");
-    let template = fs::read_to_string(&dag_template).unwrap();
-
-    info!("Rendering the dag template to {}", destfile.display());
-    match dag::render_template(&template, &variable_file, hostname) {
-      Some(result) => {
-        fs::write(&destfile, result).unwrap();
-        // Initialize (Upload biopoem and dag file.)
-        let port = host.port().parse().unwrap();
-        let remote_workdir = &args.remote_workdir;
-        let biopoem_bin_url = "http://nordata-cdn.oss-cn-shanghai.aliyuncs.com/biopoem/biopoem";
-        let session = remote::init_session(host.ipaddr(), port, host.username(), &keyfile)
-          .await
-          .unwrap();
-
-        remote::init_env(&session, remote_workdir, &destfile, biopoem_bin_url).await;
-        remote::launch_biopoem(&session, remote_workdir, "", 3000).await;
-        match session.close().await {
-          Err(msg) => warn!("{}", msg),
-          _ => {}
-        };
-      }
-      None => {
-        error!(
-          "Not found a context in {} with {}",
-          variable_file.display(),
-          hostname
-        );
+  // All clients of a run share one secret key, query reads it from the state file.
+  // A dry run doesn't create the state file.
+  let mut state = match args.dry_run {
+    true => RunState::read(".").unwrap_or_else(|_| RunState::new()),
+    false => RunState::load_or_create(".").unwrap_or_else(|msg| {
+      error!("{}", msg);
+      process::exit(biopoem_api::PROC_OTHER_ERROR);
+    }),
+  };
+
+  let mut hosts = server::host::read_hosts(&args.hosts);
+  write_ssh_config(&ssh, &hosts);
+  let all_hostnames: Vec<String> = hosts.iter().map(|host| host.hostname().to_string()).collect();
+  if let Some(hosts_filter) = &args.hosts_filter {
+    let hostnames: Vec<&str> = hosts_filter.split(',').map(|h| h.trim()).collect();
+    hosts.retain(|host| hostnames.contains(&host.hostname()));
+  }
+
+  if args.only_failed {
+    let secret_key = state.secret_key().to_string();
+    hosts = select_failed(hosts, &secret_key, &ssh, &mut state).await;
+  }
+
+  let template = fs::read_to_string(&dag_template).unwrap();
+  let results = render_dags(&hosts, &all_hostnames, &template, &variables);
+  if args.dry_run {
+    dry_run(
+      &hosts,
+      &results,
+      &variables,
+      &args.remote_workdir,
+      &binary,
+      &supervision,
+    );
+    return;
+  }
+
+  let dags = check_dags(&hosts, results);
+  let ca = match args.tls {
+    true => match Ca::load_or_create(".") {
+      Err(msg) => {
+        error!("{}", msg);
+        process::exit(biopoem_api::PROC_OTHER_ERROR);
       }
-    };
+      Ok(ca) => Some(ca),
+    },
+    false => None,
+  };
+  let concurrency = args.concurrency.max(1);
+  info!(
+    "Deploy {} hosts, {} at the same time.",
+    hosts.len(),
+    concurrency
+  );
+
+  let mut outcomes: Vec<Outcome> = stream::iter(hosts.iter().zip(dags.iter()).enumerate())
+    .map(|(index, (host, dag))| {
+      deploy(
+        index,
+        host,
+        dag,
+        &ssh,
+        &args.remote_workdir,
+        &binary,
+        state.secret_key(),
+        &supervision,
+        ca.as_ref(),
+      )
+    })
+    .buffer_unordered(concurrency)
+    .collect()
+    .await;
+  outcomes.sort_by_key(|outcome| outcome.index);
+
+  for outcome in &outcomes {
+    state.record_launch(
+      &outcome.hostname,
+      &outcome.stage.to_string(),
+      outcome.error.clone(),
+      args.local_api,
+      args.tls,
+    );
+  }
+  if let Err(msg) = state.write(".") {
+    error!("{}", msg);
+  }
+
+  print_summary(&outcomes);
+  if outcomes.iter().any(|outcome| outcome.error.is_some()) {
+    process::exit(biopoem_api::PROC_EXEC_ERROR);
   }
 }
@@ -0,0 +1,197 @@
+use super::{ca_cert, init_logger, optional_ssh_options, write_ssh_config, SshArgs};
+use biopoem_api::server::state::RunState;
+use biopoem_api::server::remote::SshOptions;
+use biopoem_api::{server, server::api, server::host::Host, server::remote, server::tunnel};
+use futures::stream::{self, StreamExt};
+use prettytable::Table;
+use std::time::Duration;
+use std::process;
+use structopt::StructOpt;
+
+/// Stop running DAGs for Biopoem
+#[derive(StructOpt, PartialEq, Debug)]
+#[structopt(setting=structopt::clap::AppSettings::ColoredHelp, name="Biopoem - Stop", author="Jingcheng Yang <yjcyxky@163.com>")]
+pub struct Arguments {
+  /// The host file.
+  #[structopt(name = "hosts", short = "-H", long = "hosts", default_value = "hosts")]
+  hosts: String,
+
+  /// Stop the hosts only, all hosts in the host file by default.
+  #[structopt(name = "hostname", short = "n", long = "hostname")]
+  hostnames: Vec<String>,
+
+  /// The working directory of the server subcommand, the secret key is read from it.
+  #[structopt(name = "workdir", short = "w", long = "workdir", default_value = ".")]
+  workdir: String,
+
+  /// Secret key, it will override the one in the working directory.
+  #[structopt(name = "secret-key", short = "s", long = "secret-key")]
+  secret_key: Option<String>,
+
+  #[structopt(flatten)]
+  ssh: SshArgs,
+
+  /// The working directory on remote machine.
+  #[structopt(
+    name = "remote-workdir",
+    short = "r",
+    long = "remote-workdir",
+    default_value = "/mnt/biopoem"
+  )]
+  remote_workdir: String,
+
+  /// Reach the clients through ssh tunnels, it is on if the server subcommand launched them with --local-api.
+  #[structopt(name = "tunnel", long = "tunnel")]
+  tunnel: bool,
+
+  /// The CA certificate for verifying clients which serve https, <workdir>/ca.pem is used if the server subcommand launched them with --tls.
+  #[structopt(name = "ca-cert", long = "ca-cert")]
+  ca_cert: Option<String>,
+}
+
+async fn stop_by_ssh(host: &Host, ssh: &SshOptions, remote_workdir: &str) -> Result<(), String> {
+  let port = host
+    .port()
+    .parse::<u16>()
+    .map_err(|msg| format!("Invalid port {}, {}", host.port(), msg))?;
+  let session = remote::init_session(host.ipaddr(), port, host.username(), ssh)
+    .await
+    .map_err(|msg| format!("Cannot connect {}, {}", host.ipaddr(), msg))?;
+
+  let result = match remote::kill_biopoem(&session, remote_workdir).await {
+    Ok(_) => remote::write_status(&session, remote_workdir, "Cancelled").await,
+    Err(msg) => Err(msg),
+  };
+  match session.close().await {
+    Err(msg) => warn!("{}", msg),
+    _ => {}
+  };
+
+  result
+}
+
+/// Cancel the DAG by the client API, Err if the client cannot be reached.
+async fn cancel(
+  host: &Host,
+  client: &reqwest::Client,
+  tls: bool,
+  secret_key: &str,
+  ssh: Option<&SshOptions>,
+  local_api: bool,
+) -> Result<Result<String, String>, String> {
+  let endpoint = tunnel::api_endpoint(host, ssh, local_api).await?;
+  let base_url = api::base_url(&endpoint.ipaddr, endpoint.port, tls);
+  let cancel_url = api::api_url(&base_url, "api/v1/cancel", secret_key);
+
+  let response = client
+    .post(cancel_url)
+    .send()
+    .await
+    .map_err(|msg| msg.to_string())?;
+  let status = response.status();
+  let body = response.text().await.unwrap_or_default();
+  Ok(match status {
+    reqwest::StatusCode::OK => Ok("Cancelled".to_string()),
+    reqwest::StatusCode::CONFLICT => Ok(body),
+    _ => Err(format!("{}, {}", status, body)),
+  })
+}
+
+/// Returns the method which stopped the host and what happened.
+async fn stop(
+  host: &Host,
+  client: &reqwest::Client,
+  tls: bool,
+  secret_key: &str,
+  ssh: &Option<SshOptions>,
+  local_api: bool,
+  remote_workdir: &str,
+) -> (&'static str, Result<String, String>) {
+  match cancel(host, client, tls, secret_key, ssh.as_ref(), local_api).await {
+    Ok(result) => ("api", result),
+    Err(msg) => {
+      warn!("{}: cannot reach the client, {}", host.hostname(), msg);
+      match ssh {
+        Some(ssh) => (
+          "ssh",
+          stop_by_ssh(host, ssh, remote_workdir)
+            .await
+            .map(|_| "Killed".to_string()),
+        ),
+        None => ("api", Err(format!("Cannot reach the client, {}", msg))),
+      }
+    }
+  }
+}
+
+#[tokio::main]
+pub async fn run(args: &Arguments) {
+  if let Err(log) = init_logger("Stop") {
+    error!(target:"stdout", "Log initialization error, {}", log);
+    process::exit(biopoem_api::PROC_OTHER_ERROR);
+  };
+
+  let state = RunState::read(&args.workdir);
+  let secret_key = match (&args.secret_key, &state) {
+    (Some(secret_key), _) => secret_key.to_string(),
+    (None, Ok(state)) => state.secret_key().to_string(),
+    (None, Err(msg)) => {
+      error!("{}, please specify the working directory of the server subcommand or the secret key.", msg);
+      process::exit(biopoem_api::PROC_OTHER_ERROR);
+    }
+  };
+  let state = state.ok();
+  let any_tls = state.as_ref().map_or(false, |state| state.any_tls());
+  let ca_cert = ca_cert(&args.ca_cert, any_tls, &args.workdir);
+
+  let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(10));
+  if let Some(ca_cert) = ca_cert {
+    builder = builder.add_root_certificate(ca_cert);
+  }
+  let client = builder.build().unwrap();
+
+  let hosts: Vec<Host> = server::host::read_hosts(&args.hosts)
+    .into_iter()
+    .filter(|host| args.hostnames.len() == 0 || args.hostnames.iter().any(|h| h == host.hostname()))
+    .collect();
+  // Without a keyfile, hosts whose API is unreachable are reported only.
+  let ssh = optional_ssh_options(&args.ssh, &args.workdir, &hosts);
+  if let Some(ssh) = &ssh {
+    write_ssh_config(ssh, &hosts);
+  }
+
+  let (client, secret_key, ssh, remote_workdir, state) =
+    (&client, &secret_key, &ssh, &args.remote_workdir, &state);
+  let mut results: Vec<(usize, &Host, (&str, Result<String, String>))> =
+    stream::iter(hosts.iter().enumerate())
+      .map(|(index, host)| async move {
+        let local_api =
+          args.tunnel || state.as_ref().map_or(false, |state| state.local_api(host.hostname()));
+        // Clients serve https if they were launched with --tls, or all of them with --ca-cert.
+        let tls =
+          args.ca_cert.is_some() || state.as_ref().map_or(false, |state| state.tls(host.hostname()));
+        (
+          index,
+          host,
+          stop(host, client, tls, secret_key, ssh, local_api, remote_workdir).await,
+        )
+      })
+      .buffer_unordered(10)
+      .collect()
+      .await;
+  results.sort_by_key(|(index, _, _)| *index);
+
+  let mut table = Table::new();
+  table.add_row(row!["hostname", "method", "result", "message"]);
+  for (_, host, (method, result)) in &results {
+    match result {
+      Ok(msg) => table.add_row(row![host.hostname(), method, "Success", msg]),
+      Err(msg) => table.add_row(row![host.hostname(), method, "Failed", msg]),
+    };
+  }
+  table.printstd();
+
+  if results.iter().any(|(_, _, (_, result))| result.is_err()) {
+    process::exit(biopoem_api::PROC_EXEC_ERROR);
+  }
+}
@@ -0,0 +1,111 @@
+use super::{init_logger, notexists_exit};
+use biopoem_api::server::{self, dag};
+use prettytable::Table;
+use std::path::{Path, PathBuf};
+use std::{fs, process};
+use structopt::StructOpt;
+
+/// Validate the DAGs of all hosts for Biopoem
+#[derive(StructOpt, PartialEq, Debug)]
+#[structopt(setting=structopt::clap::AppSettings::ColoredHelp, name="Biopoem - Validate", author="Jingcheng Yang <yjcyxky@163.com>")]
+pub struct Arguments {
+  /// The host file.
+  #[structopt(name = "hosts", short = "-H", long = "hosts", default_value = "hosts")]
+  hosts: String,
+
+  /// The template file for DAG.
+  #[structopt(
+    name = "dag-template",
+    short = "t",
+    long = "dag-template",
+    default_value = "dag.template"
+  )]
+  dag_template: String,
+
+  /// The variable file for DAG (json, yaml, toml, csv or tsv).
+  #[structopt(
+    name = "variable-file",
+    short = "f",
+    long = "variable-file",
+    default_value = "variables"
+  )]
+  variable_file: String,
+
+  /// The format of the variable file, it is detected by the extension (json without extension) by default.
+  #[structopt(name = "variables-format", long = "variables-format", possible_values=&dag::FORMATS)]
+  variables_format: Option<String>,
+
+  /// Save the rendered DAGs into <output-dir>/<hostname>/dag.factfile.
+  #[structopt(name = "output-dir", short = "o", long = "output-dir")]
+  output_dir: Option<String>,
+}
+
+pub fn run(args: &Arguments) {
+  if let Err(log) = init_logger("Validate") {
+    error!(target:"stdout", "Log initialization error, {}", log);
+    process::exit(biopoem_api::PROC_OTHER_ERROR);
+  };
+
+  for filepath in vec![&args.hosts, &args.dag_template, &args.variable_file] {
+    notexists_exit(
+      &PathBuf::from(filepath),
+      &format!("No such file: {} file doesn't exist.", filepath),
+    );
+  }
+
+  let template = match fs::read_to_string(&args.dag_template) {
+    Err(msg) => {
+      error!("Cannot read {}, {}", &args.dag_template, msg);
+      process::exit(biopoem_api::PROC_OTHER_ERROR);
+    }
+    Ok(template) => template,
+  };
+
+  let varpath = PathBuf::from(&args.variable_file);
+  let format = match &args.variables_format {
+    Some(format) => format.as_str(),
+    None => match dag::detect_format(&varpath) {
+      Err(msg) => {
+        error!("{}, please use --variables-format.", msg);
+        process::exit(biopoem_api::PROC_PARSE_ERROR);
+      }
+      Ok(format) => format,
+    },
+  };
+  let variables = match dag::read_variables(&varpath, format) {
+    Err(msg) => {
+      error!("{}", msg);
+      process::exit(biopoem_api::PROC_PARSE_ERROR);
+    }
+    Ok(variables) => variables,
+  };
+
+  let hosts = server::host::read_hosts(&args.hosts);
+  let mut table = Table::new();
+  table.add_row(row!["hostname", "result", "errors"]);
+  let mut num_invalid = 0;
+  for (index, host) in hosts.iter().enumerate() {
+    match dag::render_and_validate(&template, &variables, host, index, hosts.len()) {
+      Ok(content) => {
+        table.add_row(row![host.hostname(), "Valid", ""]);
+        if let Some(output_dir) = &args.output_dir {
+          let subdir = Path::new(output_dir).join(host.hostname());
+          let destfile = subdir.join("dag.factfile");
+          if let Err(msg) = fs::create_dir_all(&subdir).and_then(|_| fs::write(&destfile, content)) {
+            error!("Cannot write {}, {}", destfile.display(), msg);
+          }
+        }
+      }
+      Err(errors) => {
+        num_invalid += 1;
+        table.add_row(row![host.hostname(), "Invalid", errors.join("\n")]);
+      }
+    }
+  }
+
+  table.printstd();
+  if num_invalid > 0 {
+    error!("{} of {} DAGs are invalid.", num_invalid, hosts.len());
+    process::exit(biopoem_api::PROC_PARSE_ERROR);
+  }
+}
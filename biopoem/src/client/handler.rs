@@ -1,64 +1,274 @@
-use poem::{handler, web::Query, Result};
+use crate::client::logs::{self, LogChunk};
+use crate::client::model::{JobUpdate, SharedStatus, State};
+use crate::client::process;
+use crate::client::resume;
+use futures::stream;
+use log::{info, warn};
+use poem::{
+  handler,
+  http::StatusCode,
+  web::{
+    sse::{Event, SSE},
+    Data, Json, Path, Query,
+  },
+  IntoResponse, Response, Result,
+};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::fs;
+use std::time::Duration;
+
+/// How long the tasks have to exit after SIGTERM before they are killed.
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// How often a followed log is checked for new lines.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The max size of an event in follow mode.
+const CHUNK_SIZE: u64 = 64 * 1024;
 
 #[derive(Debug, Deserialize)]
 pub struct Params {
   secret_key: String,
 }
 
-fn check_secret_key(secret_key: String) -> bool {
-  return secret_key == "biopoem-N8kOaPq6".to_string();
+#[derive(Debug, Deserialize)]
+pub struct LogParams {
+  secret_key: String,
+  /// Read the log from the byte offset.
+  from_offset: Option<u64>,
+  /// Read the last lines of the log, from_offset takes precedence.
+  lines: Option<usize>,
+  /// Keep sending new lines as Server-Sent Events.
+  #[serde(default)]
+  follow: bool,
 }
 
-fn get_secret_key(res: Result<Query<Params>>) -> String {
+/// The secret key which the client was launched with.
+#[derive(Debug, Clone)]
+pub struct SecretKey(pub String);
+
+/// The webhook which the job updates of factotum are forwarded to.
+#[derive(Debug, Clone)]
+pub struct Webhook(pub Option<String>);
+
+/// Compare the SHA-256 digests of the keys, so the time taken doesn't depend on how many
+/// leading characters of the given key are right.
+fn is_secret_key(given: &str, secret_key: &SecretKey) -> bool {
+  let given = Sha256::digest(given.as_bytes());
+  let expected = Sha256::digest(secret_key.0.as_bytes());
+  given
+    .iter()
+    .zip(expected.iter())
+    .fold(0, |diff, (a, b)| diff | (a ^ b))
+    == 0
+}
+
+fn check_secret_key(res: Result<Query<Params>>, secret_key: &SecretKey) -> bool {
   return match res {
-    Ok(Query(params)) => params.secret_key,
-    Err(err) => "Not valid query".to_string(),
+    Ok(Query(params)) => is_secret_key(&params.secret_key, secret_key),
+    Err(_) => false,
   };
 }
 
+fn unauthorized() -> Response {
+  Response::builder()
+    .status(StatusCode::UNAUTHORIZED)
+    .body("Authentication Failed.")
+}
+
 #[handler]
-pub async fn status(res: Result<Query<Params>>) -> String {
-  let secret_key = get_secret_key(res);
-  if check_secret_key(secret_key) {
+pub async fn status(res: Result<Query<Params>>, secret_key: Data<&SecretKey>) -> Response {
+  if check_secret_key(res, &secret_key) {
     let status = match fs::read_to_string("status") {
-      Err(msg) => "Running".to_string(),
+      Err(_) => "Running".to_string(),
       Ok(msg) => msg,
     };
 
-    return status;
+    return Response::builder().body(status);
   } else {
-    return "Authentication Failed.".to_string();
+    return unauthorized();
   }
 }
 
 #[handler]
-pub async fn client_log(res: Result<Query<Params>>) -> String {
-  let secret_key = get_secret_key(res);
-  if check_secret_key(secret_key) {
+pub async fn client_log(res: Result<Query<Params>>, secret_key: Data<&SecretKey>) -> Response {
+  if check_secret_key(res, &secret_key) {
     let task_log = match fs::read_to_string("client.log") {
       Err(msg) => msg.to_string(),
       Ok(msg) => msg,
     };
 
-    return task_log;
+    return Response::builder().body(task_log);
   } else {
-    return "Authentication Failed.".to_string();
+    return unauthorized();
   }
 }
 
 #[handler]
-pub async fn init_log(res: Result<Query<Params>>) -> String {
-  let secret_key = get_secret_key(res);
-  if check_secret_key(secret_key) {
+pub async fn init_log(res: Result<Query<Params>>, secret_key: Data<&SecretKey>) -> Response {
+  if check_secret_key(res, &secret_key) {
     let init_log = match fs::read_to_string("init.log") {
       Err(msg) => msg.to_string(),
       Ok(msg) => msg,
     };
 
-    return init_log;
+    return Response::builder().body(init_log);
   } else {
-    return "Authentication Failed.".to_string();
+    return unauthorized();
+  }
+}
+
+/// Tail the log, the X-Log-Offset header is where the next request continues. The events in
+/// follow mode carry a LogChunk in JSON.
+#[handler]
+pub async fn api_log(
+  Path(name): Path<String>,
+  res: Result<Query<LogParams>>,
+  secret_key: Data<&SecretKey>,
+) -> Response {
+  let params = match res {
+    Ok(Query(params)) if is_secret_key(&params.secret_key, &secret_key) => params,
+    _ => return unauthorized(),
+  };
+
+  let logfile = match logs::log_file(&name) {
+    Some(logfile) => logfile,
+    None => {
+      return Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(format!("No such log {}.", name));
+    }
+  };
+
+  let offset = match (params.from_offset, params.lines) {
+    (Some(offset), _) => offset,
+    (None, Some(lines)) => logs::tail_offset(logfile, lines).unwrap_or(0),
+    (None, None) => 0,
+  };
+
+  if !params.follow {
+    return match logs::read_chunk(logfile, offset, u64::MAX) {
+      Err(msg) => Response::builder().status(StatusCode::NOT_FOUND).body(msg),
+      Ok((data, end)) => Response::builder()
+        .header("X-Log-Offset", end.to_string())
+        .body(data),
+    };
   }
+
+  let events = stream::unfold(offset, move |mut offset| async move {
+    loop {
+      match logs::read_chunk(logfile, offset, CHUNK_SIZE) {
+        Ok((data, end)) if data != "" => {
+          let chunk = serde_json::to_string(&LogChunk { offset: end, data: data }).unwrap();
+          return Some((Event::message(chunk).id(end.to_string()), end));
+        }
+        Ok((_, end)) => offset = end,
+        // The log may not be created yet.
+        Err(_) => {}
+      }
+
+      tokio::time::sleep(POLL_INTERVAL).await;
+    }
+  });
+
+  SSE::new(events)
+    .keep_alive(Duration::from_secs(15))
+    .into_response()
+}
+
+#[handler]
+pub async fn api_status(
+  res: Result<Query<Params>>,
+  secret_key: Data<&SecretKey>,
+  // #[handler] turns status into a unit struct, a binding named status would be a pattern of it.
+  shared_status: Data<&SharedStatus>,
+) -> Response {
+  if check_secret_key(res, &secret_key) {
+    return Json(shared_status.get()).into_response();
+  } else {
+    return unauthorized();
+  }
+}
+
+/// Factotum posts job updates here, they are recorded and forwarded to the user's webhook.
+#[handler]
+pub async fn webhook(
+  res: Result<Query<Params>>,
+  secret_key: Data<&SecretKey>,
+  shared_status: Data<&SharedStatus>,
+  forward: Data<&Webhook>,
+  body: String,
+) -> Response {
+  if !check_secret_key(res, &secret_key) {
+    return unauthorized();
+  }
+
+  match serde_json::from_str::<JobUpdate>(&body) {
+    Err(msg) => warn!("Cannot parse the job update, {}", msg),
+    Ok(update) => {
+      shared_status.update(|run_status| run_status.update_tasks(&update));
+
+      for task_state in &update.data.task_states {
+        if State::from_factotum(&task_state.state) == State::Success {
+          if let Err(msg) = resume::mark_done(&task_state.task_name) {
+            warn!("{}", msg);
+          }
+        }
+      }
+
+      // Factotum may still start independent tasks after the run is cancelled.
+      if shared_status.get().state == State::Cancelled {
+        process::terminate_descendants(GRACE_PERIOD);
+      }
+    }
+  };
+
+  let forward: &Webhook = &forward;
+  if let Some(webhook_url) = &forward.0 {
+    let webhook_url = webhook_url.clone();
+    tokio::spawn(async move {
+      let client = reqwest::Client::new();
+      if let Err(msg) = client
+        .post(&webhook_url)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .send()
+        .await
+      {
+        warn!("Cannot forward the job update to {}, {}", webhook_url, msg);
+      }
+    });
+  }
+
+  return Response::builder().body("OK");
+}
+
+/// Terminate the running DAG and all processes of its tasks.
+#[handler]
+pub async fn cancel(
+  res: Result<Query<Params>>,
+  secret_key: Data<&SecretKey>,
+  shared_status: Data<&SharedStatus>,
+) -> Response {
+  if !check_secret_key(res, &secret_key) {
+    return unauthorized();
+  }
+
+  let state = shared_status.get().state;
+  if state != State::Running {
+    return Response::builder()
+      .status(StatusCode::CONFLICT)
+      .body(format!("The run is {}, nothing to cancel.", state));
+  }
+
+  shared_status.update(|run_status| run_status.cancel());
+  if let Err(msg) = fs::write("status", State::Cancelled.to_string()) {
+    warn!("Cannot write status, {}", msg);
+  }
+
+  let num = process::terminate_descendants(GRACE_PERIOD);
+  info!("The run is cancelled, {} processes are terminated.", num);
+
+  return Json(shared_status.get()).into_response();
 }
@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+
+/// A piece of the log and the offset of its end, it is the data of an event in follow mode.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogChunk {
+  pub offset: u64,
+  pub data: String,
+}
+
+/// The log file which is served as the name.
+pub fn log_file(name: &str) -> Option<&'static str> {
+  match name {
+    "client" => Some("client.log"),
+    "init" => Some("init.log"),
+    _ => None,
+  }
+}
+
+fn file_size(filepath: &str) -> u64 {
+  match fs::metadata(filepath) {
+    Ok(metadata) => metadata.len(),
+    Err(_) => 0,
+  }
+}
+
+/// The offset where the last lines of the file start.
+pub fn tail_offset(filepath: &str, lines: usize) -> Result<u64, String> {
+  let size = file_size(filepath);
+  if lines == 0 || size == 0 {
+    return Ok(size);
+  }
+
+  let mut file =
+    File::open(filepath).map_err(|msg| format!("Cannot open {}, {}", filepath, msg))?;
+  let mut buf = [0u8; 8192];
+  let mut pos = size;
+  let mut count = 0;
+  while pos > 0 {
+    let len = pos.min(buf.len() as u64);
+    pos -= len;
+    file
+      .seek(SeekFrom::Start(pos))
+      .and_then(|_| file.read_exact(&mut buf[..len as usize]))
+      .map_err(|msg| format!("Cannot read {}, {}", filepath, msg))?;
+
+    for i in (0..len as usize).rev() {
+      // The newline at the end of the file ends the last line, it doesn't start a new one.
+      if buf[i] == b'\n' && pos + i as u64 + 1 != size {
+        count += 1;
+        if count == lines {
+          return Ok(pos + i as u64 + 1);
+        }
+      }
+    }
+  }
+
+  Ok(0)
+}
+
+/// Read at most limit bytes from the offset, returns the text and the offset of its end.
+/// A file which is shorter than the offset was truncated, it is read from the beginning.
+pub fn read_chunk(filepath: &str, offset: u64, limit: u64) -> Result<(String, u64), String> {
+  let mut file =
+    File::open(filepath).map_err(|msg| format!("Cannot open {}, {}", filepath, msg))?;
+  let size = file
+    .metadata()
+    .map_err(|msg| format!("Cannot read {}, {}", filepath, msg))?
+    .len();
+  let offset = match offset > size {
+    true => 0,
+    false => offset,
+  };
+
+  let mut buf: Vec<u8> = vec![];
+  file
+    .seek(SeekFrom::Start(offset))
+    .and_then(|_| (&mut file).take((size - offset).min(limit)).read_to_end(&mut buf))
+    .map_err(|msg| format!("Cannot read {}, {}", filepath, msg))?;
+
+  // Keep whole lines when the chunk is cut by the limit.
+  if buf.len() as u64 == limit {
+    if let Some(pos) = buf.iter().rposition(|b| *b == b'\n') {
+      buf.truncate(pos + 1);
+    }
+  }
+
+  let end = offset + buf.len() as u64;
+  Ok((String::from_utf8_lossy(&buf).to_string(), end))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::PathBuf;
+
+  fn write_log(name: &str, content: &str) -> PathBuf {
+    let filepath =
+      std::env::temp_dir().join(format!("biopoem-logs-{}-{}.log", std::process::id(), name));
+    fs::write(&filepath, content).unwrap();
+    filepath
+  }
+
+  #[test]
+  fn test_tail_offset() {
+    let filepath = write_log("tail", "a\nbb\nccc\n");
+    let filepath = filepath.to_str().unwrap();
+    assert_eq!(tail_offset(filepath, 0).unwrap(), 9);
+    assert_eq!(tail_offset(filepath, 1).unwrap(), 5);
+    assert_eq!(tail_offset(filepath, 2).unwrap(), 2);
+    assert_eq!(tail_offset(filepath, 3).unwrap(), 0);
+    assert_eq!(tail_offset(filepath, 10).unwrap(), 0);
+    fs::remove_file(filepath).unwrap();
+  }
+
+  #[test]
+  fn test_tail_offset_without_trailing_newline() {
+    let filepath = write_log("partial", "a\nbb\ncc");
+    let filepath = filepath.to_str().unwrap();
+    assert_eq!(tail_offset(filepath, 1).unwrap(), 5);
+    assert_eq!(tail_offset(filepath, 2).unwrap(), 2);
+    fs::remove_file(filepath).unwrap();
+  }
+
+  #[test]
+  fn test_tail_offset_across_buffers() {
+    let line = format!("{}\n", "x".repeat(99));
+    let filepath = write_log("long", &line.repeat(200));
+    let filepath = filepath.to_str().unwrap();
+    assert_eq!(tail_offset(filepath, 100).unwrap(), 100 * 100);
+    fs::remove_file(filepath).unwrap();
+  }
+
+  #[test]
+  fn test_tail_offset_missing_file() {
+    assert_eq!(tail_offset("/nonexistent/biopoem/client.log", 10).unwrap(), 0);
+  }
+
+  #[test]
+  fn test_read_chunk() {
+    let filepath = write_log("chunk", "a\nbb\nccc\n");
+    let filepath = filepath.to_str().unwrap();
+    assert_eq!(read_chunk(filepath, 0, 1024).unwrap(), ("a\nbb\nccc\n".to_string(), 9));
+    assert_eq!(read_chunk(filepath, 2, 1024).unwrap(), ("bb\nccc\n".to_string(), 9));
+    assert_eq!(read_chunk(filepath, 9, 1024).unwrap(), ("".to_string(), 9));
+    fs::remove_file(filepath).unwrap();
+  }
+
+  #[test]
+  fn test_read_chunk_keeps_whole_lines() {
+    let filepath = write_log("limit", "a\nbb\nccc\n");
+    let filepath = filepath.to_str().unwrap();
+    assert_eq!(read_chunk(filepath, 0, 7).unwrap(), ("a\nbb\n".to_string(), 5));
+    // A line longer than the limit is returned in pieces.
+    assert_eq!(read_chunk(filepath, 5, 2).unwrap(), ("cc".to_string(), 7));
+    fs::remove_file(filepath).unwrap();
+  }
+
+  #[test]
+  fn test_read_chunk_truncated_file() {
+    let filepath = write_log("truncated", "a\n");
+    let filepath = filepath.to_str().unwrap();
+    assert_eq!(read_chunk(filepath, 100, 1024).unwrap(), ("a\n".to_string(), 2));
+    fs::remove_file(filepath).unwrap();
+  }
+}
@@ -1,2 +1,6 @@
 pub mod handler;
+pub mod logs;
+pub mod model;
+pub mod process;
+pub mod resume;
 pub mod route;
\ No newline at end of file
@@ -0,0 +1,232 @@
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+/// The file in the client's working directory which records the status of the run.
+pub const STATUS_FILE: &str = "status.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum State {
+  Waiting,
+  Running,
+  Success,
+  Failed,
+  Skipped,
+  Cancelled,
+}
+
+impl State {
+  /// Map the task state in factotum's job update to biopoem's.
+  pub fn from_factotum(state: &str) -> Self {
+    match state {
+      "RUNNING" => State::Running,
+      "SUCCEEDED" | "SUCCESS" => State::Success,
+      "FAILED" => State::Failed,
+      "SKIPPED" => State::Skipped,
+      _ => State::Waiting,
+    }
+  }
+}
+
+impl fmt::Display for State {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:?}", self)
+  }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TaskStatus {
+  pub name: String,
+  pub state: State,
+  pub started_at: Option<String>,
+  pub duration: Option<String>,
+  pub return_code: Option<i32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RunStatus {
+  pub state: State,
+  pub started_at: String,
+  pub finished_at: Option<String>,
+  pub exit_code: Option<i32>,
+  pub tasks: Vec<TaskStatus>,
+}
+
+impl RunStatus {
+  /// A running status with all tasks in the factfile waiting.
+  pub fn from_factfile(factfile: &str) -> Result<Self, String> {
+    let data = fs::read_to_string(factfile)
+      .map_err(|msg| format!("Cannot read {}, {}", factfile, msg))?;
+    let value: Value =
+      serde_json::from_str(&data).map_err(|msg| format!("Cannot parse {}, {}", factfile, msg))?;
+
+    let tasks = match value["data"]["tasks"].as_array() {
+      Some(tasks) => tasks
+        .iter()
+        .filter_map(|task| task["name"].as_str())
+        .map(|name| TaskStatus {
+          name: name.to_string(),
+          state: State::Waiting,
+          started_at: None,
+          duration: None,
+          return_code: None,
+        })
+        .collect(),
+      None => vec![],
+    };
+
+    Ok(RunStatus {
+      state: State::Running,
+      started_at: chrono::Local::now().to_rfc3339(),
+      finished_at: None,
+      exit_code: None,
+      tasks: tasks,
+    })
+  }
+
+  /// Tasks which succeeded before the client restarted.
+  pub fn mark_done(&mut self, done: &HashSet<String>) {
+    for task in self.tasks.iter_mut() {
+      if done.contains(&task.name) {
+        task.state = State::Success;
+      }
+    }
+  }
+
+  pub fn read(filepath: &str) -> Result<Self, String> {
+    let data = fs::read_to_string(filepath)
+      .map_err(|msg| format!("Cannot read {}, {}", filepath, msg))?;
+    serde_json::from_str(&data).map_err(|msg| format!("Cannot parse {}, {}", filepath, msg))
+  }
+
+  pub fn write(&self, filepath: &str) -> Result<(), String> {
+    let data = serde_json::to_string_pretty(self).unwrap();
+    fs::write(filepath, data).map_err(|msg| format!("Cannot write {}, {}", filepath, msg))
+  }
+
+  /// Merge the task states which factotum reports to the webhook.
+  pub fn update_tasks(&mut self, update: &JobUpdate) {
+    for task_state in &update.data.task_states {
+      let index = match self.tasks.iter().position(|t| t.name == task_state.task_name) {
+        Some(index) => index,
+        None => {
+          self.tasks.push(TaskStatus {
+            name: task_state.task_name.clone(),
+            state: State::Waiting,
+            started_at: None,
+            duration: None,
+            return_code: None,
+          });
+          self.tasks.len() - 1
+        }
+      };
+
+      let task = &mut self.tasks[index];
+      task.state = State::from_factotum(&task_state.state);
+      task.started_at = task_state.started.clone();
+      task.duration = task_state.duration.clone();
+      task.return_code = task_state.return_code;
+    }
+  }
+
+  /// Such as "2/5 tasks finished, running: Download file".
+  pub fn summary(&self) -> String {
+    let finished = self
+      .tasks
+      .iter()
+      .filter(|t| t.state != State::Waiting && t.state != State::Running)
+      .count();
+    let running: Vec<&str> = self
+      .tasks
+      .iter()
+      .filter(|t| t.state == State::Running)
+      .map(|t| t.name.as_str())
+      .collect();
+
+    match running.len() {
+      0 => format!("{}/{} tasks finished", finished, self.tasks.len()),
+      _ => format!(
+        "{}/{} tasks finished, running: {}",
+        finished,
+        self.tasks.len(),
+        running.join(", ")
+      ),
+    }
+  }
+
+  pub fn finish(&mut self, exit_code: i32) {
+    self.exit_code = Some(exit_code);
+    // A cancelled run stays cancelled, although its tasks fail by being killed.
+    if self.state == State::Cancelled {
+      return;
+    }
+
+    self.state = match exit_code == 0 {
+      true => State::Success,
+      false => State::Failed,
+    };
+    self.finished_at = Some(chrono::Local::now().to_rfc3339());
+  }
+
+  pub fn cancel(&mut self) {
+    self.state = State::Cancelled;
+    self.finished_at = Some(chrono::Local::now().to_rfc3339());
+    for task in self.tasks.iter_mut() {
+      if task.state == State::Waiting || task.state == State::Running {
+        task.state = State::Cancelled;
+      }
+    }
+  }
+}
+
+/// The status of the run which is shared by the DAG engine and the handlers.
+#[derive(Debug, Clone)]
+pub struct SharedStatus(Arc<Mutex<RunStatus>>);
+
+impl SharedStatus {
+  pub fn new(status: RunStatus) -> Self {
+    let shared = SharedStatus(Arc::new(Mutex::new(status)));
+    shared.update(|_| {});
+    shared
+  }
+
+  pub fn get(&self) -> RunStatus {
+    self.0.lock().unwrap().clone()
+  }
+
+  /// Change the status and save it into the status file.
+  pub fn update<F: FnOnce(&mut RunStatus)>(&self, f: F) {
+    let mut status = self.0.lock().unwrap();
+    f(&mut status);
+    if let Err(msg) = status.write(STATUS_FILE) {
+      error!("{}", msg);
+    }
+  }
+}
+
+/// The job update which factotum posts to the webhook, only the fields used by biopoem.
+#[derive(Debug, Deserialize)]
+pub struct JobUpdate {
+  pub data: JobUpdateData,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobUpdateData {
+  #[serde(default)]
+  pub task_states: Vec<FactotumTaskState>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FactotumTaskState {
+  pub task_name: String,
+  pub state: String,
+  pub started: Option<String>,
+  pub duration: Option<String>,
+  pub return_code: Option<i32>,
+}
@@ -0,0 +1,75 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+/// All processes which were forked by pid directly or indirectly, read from /proc.
+pub fn descendants(pid: u32) -> Vec<u32> {
+  let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+  if let Ok(entries) = fs::read_dir("/proc") {
+    for entry in entries.flatten() {
+      let child = match entry.file_name().to_string_lossy().parse::<u32>() {
+        Ok(child) => child,
+        Err(_) => continue,
+      };
+
+      // The command name in /proc/<pid>/stat may contain spaces, the ppid follows the last ')'.
+      let stat = match fs::read_to_string(entry.path().join("stat")) {
+        Ok(stat) => stat,
+        Err(_) => continue,
+      };
+      let ppid = stat
+        .rsplit_once(')')
+        .and_then(|(_, fields)| fields.split_whitespace().nth(1))
+        .and_then(|ppid| ppid.parse::<u32>().ok());
+      if let Some(ppid) = ppid {
+        children.entry(ppid).or_insert(vec![]).push(child);
+      }
+    }
+  }
+
+  let mut pids: Vec<u32> = vec![];
+  let mut queue: Vec<u32> = vec![pid];
+  while let Some(parent) = queue.pop() {
+    if let Some(list) = children.get(&parent) {
+      for child in list {
+        pids.push(*child);
+        queue.push(*child);
+      }
+    }
+  }
+
+  pids
+}
+
+fn kill(signal: &str, pids: &Vec<u32>) {
+  if pids.len() == 0 {
+    return;
+  }
+
+  match Command::new("kill")
+    .arg(format!("-{}", signal))
+    .args(pids.iter().map(|pid| pid.to_string()))
+    .output()
+  {
+    Err(msg) => warn!("Cannot kill {:?}, {}", pids, msg),
+    Ok(_) => info!("Send SIG{} to {:?}", signal, pids),
+  };
+}
+
+/// Terminate all processes started by the client (the tasks of factotum),
+/// the ones still alive after the grace period are killed.
+pub fn terminate_descendants(grace_period: Duration) -> usize {
+  let pid = std::process::id();
+  let pids = descendants(pid);
+  kill("TERM", &pids);
+
+  thread::spawn(move || {
+    thread::sleep(grace_period);
+    kill("KILL", &descendants(pid));
+  });
+
+  pids.len()
+}
@@ -0,0 +1,96 @@
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Every succeeded task leaves a marker here, so a restarted client skips it.
+pub const MARKER_DIR: &str = ".biopoem/tasks";
+
+/// The checksum of the factfile which the markers belong to.
+const CHECKSUM_FILE: &str = ".biopoem/factfile.sha256";
+
+/// Task names may contain any characters, the marker is named by their hash.
+fn marker_path(task_name: &str) -> PathBuf {
+  let hash = format!("{:x}", Sha256::digest(task_name.as_bytes()));
+  Path::new(MARKER_DIR).join(format!("{}.done", &hash[..16]))
+}
+
+pub fn mark_done(task_name: &str) -> Result<(), String> {
+  fs::create_dir_all(MARKER_DIR)
+    .map_err(|msg| format!("Cannot create the directory {}, {}", MARKER_DIR, msg))?;
+  let filepath = marker_path(task_name);
+  fs::write(&filepath, task_name)
+    .map_err(|msg| format!("Cannot write {}, {}", filepath.display(), msg))
+}
+
+pub fn done_tasks() -> HashSet<String> {
+  let mut tasks = HashSet::new();
+  if let Ok(entries) = fs::read_dir(MARKER_DIR) {
+    for entry in entries.flatten() {
+      if let Ok(task_name) = fs::read_to_string(entry.path()) {
+        tasks.insert(task_name);
+      }
+    }
+  }
+
+  tasks
+}
+
+pub fn clear() -> Result<(), String> {
+  if Path::new(MARKER_DIR).exists() {
+    fs::remove_dir_all(MARKER_DIR)
+      .map_err(|msg| format!("Cannot remove {}, {}", MARKER_DIR, msg))?;
+  }
+
+  Ok(())
+}
+
+/// Markers of another factfile are meaningless, clear them when the factfile changes.
+pub fn check_factfile(factfile: &str) -> Result<(), String> {
+  let checksum = crate::sha256sum(Path::new(factfile))?;
+  match fs::read_to_string(CHECKSUM_FILE) {
+    Ok(previous) if previous == checksum => Ok(()),
+    _ => {
+      clear()?;
+      fs::create_dir_all(MARKER_DIR)
+        .map_err(|msg| format!("Cannot create the directory {}, {}", MARKER_DIR, msg))?;
+      fs::write(CHECKSUM_FILE, checksum)
+        .map_err(|msg| format!("Cannot write {}, {}", CHECKSUM_FILE, msg))
+    }
+  }
+}
+
+/// Write a factfile without the done tasks into dest, the dependencies on them are dropped too.
+/// Returns how many tasks are left.
+pub fn resume_factfile(factfile: &str, done: &HashSet<String>, dest: &str) -> Result<usize, String> {
+  let data =
+    fs::read_to_string(factfile).map_err(|msg| format!("Cannot read {}, {}", factfile, msg))?;
+  let mut value: Value =
+    serde_json::from_str(&data).map_err(|msg| format!("Cannot parse {}, {}", factfile, msg))?;
+
+  let tasks = match value["data"]["tasks"].as_array_mut() {
+    Some(tasks) => tasks,
+    None => return Err(format!("Not found tasks in {}", factfile)),
+  };
+
+  tasks.retain(|task| match task["name"].as_str() {
+    Some(name) => !done.contains(name),
+    None => true,
+  });
+
+  for task in tasks.iter_mut() {
+    if let Some(depends_on) = task["dependsOn"].as_array_mut() {
+      depends_on.retain(|dep| match dep.as_str() {
+        Some(dep) => !done.contains(dep),
+        None => true,
+      });
+    }
+  }
+
+  let left = tasks.len();
+  let data = serde_json::to_string_pretty(&value).unwrap();
+  fs::write(dest, data).map_err(|msg| format!("Cannot write {}, {}", dest, msg))?;
+
+  Ok(left)
+}
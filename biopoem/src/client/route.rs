@@ -1,9 +1,13 @@
 use crate::client::handler;
-use poem::{get, Route};
+use poem::{get, post, Route};
 
 pub fn init_route() -> Route {
   Route::new()
     .at("status", get(handler::status))
     .at("log/client", get(handler::client_log))
     .at("log/init", get(handler::init_log))
+    .at("api/v1/status", get(handler::api_status))
+    .at("api/v1/log/:name", get(handler::api_log))
+    .at("api/v1/webhook", post(handler::webhook))
+    .at("api/v1/cancel", post(handler::cancel))
 }
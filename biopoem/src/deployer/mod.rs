@@ -1,10 +1,18 @@
-use log::{error};
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::Ipv4Addr;
 use std::process::{Command, Output};
 use std::str;
 use tera::{Context, Tera};
 
+pub mod provider;
+
+use provider::Provider;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Host {
   hostname: String,
@@ -12,6 +20,7 @@ pub struct Host {
   private_ipaddr: String,
   port: String,
   username: String,
+  instance_id: String,
 }
 
 impl Host {
@@ -21,6 +30,7 @@ impl Host {
     private_ipaddr: String,
     port: String,
     username: String,
+    instance_id: String,
   ) -> Self {
     Host {
       hostname: hostname,
@@ -28,68 +38,343 @@ impl Host {
       private_ipaddr: private_ipaddr,
       port: port,
       username: username,
+      instance_id: instance_id,
     }
   }
 }
 
+/// The ssh host key of an instance in OpenSSH format.
+#[derive(Deserialize, Serialize)]
+pub struct HostKey {
+  private_key: String,
+  public_key: String,
+}
+
+// The config is printed after deploying, the private key is left out.
+impl fmt::Debug for HostKey {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("HostKey")
+      .field("public_key", &self.public_key)
+      .finish()
+  }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
+  provider: String,
   region: String,
   zone: String,
+  cidr_block: String,
+  /// Empty if the cloud platform assigns private IPs.
   ipaddrs: Vec<String>,
+  hostnames: Vec<String>,
   num_of_hosts: usize,
   image: String,
   instance_type: String,
   keypair_name: String,
+  /// The ssh host keys which instances take from user data, host_keys[i] belongs to
+  /// hostnames[i], empty without --host-keys.
+  host_keys: Vec<HostKey>,
 }
 
 impl Config {
   pub fn new(
+    provider: &dyn Provider,
     region: &str,
     zone: &str,
+    cidr_block: &str,
+    auto_ip: bool,
     num_of_hosts: usize,
     image: &str,
     instance_type: &str,
     keypair_name: &str,
-  ) -> Self {
-    let mut ipaddrs: Vec<String> = vec![];
-
-    if num_of_hosts > 256 {
-      error!("You cannot deploy more than 255 servers.");
-      std::process::exit(127);
-    }
+  ) -> Result<Self, String> {
+    let ipaddrs = match auto_ip {
+      true => vec![],
+      false => allocate_ipaddrs(cidr_block, num_of_hosts, provider.reserved_addresses())?,
+    };
 
-    for i in 0..num_of_hosts {
-      ipaddrs.push(format!("172.16.0.{}", i + 1));
-    }
+    let hostnames = (0..num_of_hosts)
+      .map(|idx| gen_hostname(idx, num_of_hosts))
+      .collect();
 
-    Config {
+    Ok(Config {
+      provider: provider.name().to_string(),
       region: region.to_string(),
-      zone: format!("{}-{}", region, zone),
+      zone: provider.zone(region, zone),
+      cidr_block: cidr_block.to_string(),
       ipaddrs: ipaddrs,
+      hostnames: hostnames,
       num_of_hosts: num_of_hosts,
       image: image.to_string(),
       instance_type: instance_type.to_string(),
       keypair_name: keypair_name.to_string(),
+      host_keys: vec![],
+    })
+  }
+
+  pub fn hostnames(&self) -> &Vec<String> {
+    &self.hostnames
+  }
+
+  pub fn set_host_keys(&mut self, host_keys: Vec<HostKey>) {
+    self.host_keys = host_keys;
+  }
+
+  pub fn host_keys(&self) -> &Vec<HostKey> {
+    &self.host_keys
+  }
+}
+
+/// biopoem001, biopoem002, ..., it gets wider when there are more than 999 hosts.
+pub fn gen_hostname(idx: usize, num_of_hosts: usize) -> String {
+  let width = std::cmp::max(3, num_of_hosts.to_string().len());
+  format!("biopoem{:0width$}", idx + 1, width = width)
+}
+
+pub fn parse_cidr(cidr_block: &str) -> Result<(Ipv4Addr, u32), String> {
+  let invalid = || format!("{} is not a valid CIDR block, such as 172.16.0.0/21", cidr_block);
+  let (addr, prefix) = cidr_block.split_once('/').ok_or_else(invalid)?;
+  let addr: Ipv4Addr = addr.parse().map_err(|_| invalid())?;
+  let prefix: u32 = prefix.parse().map_err(|_| invalid())?;
+  if prefix > 32 {
+    return Err(invalid());
+  }
+
+  Ok((addr, prefix))
+}
+
+/// Allocate private IPs from the CIDR block in order, skipping the addresses reserved by
+/// the cloud platform at both ends and all x.x.x.0/x.x.x.255 addresses.
+pub fn allocate_ipaddrs(
+  cidr_block: &str,
+  num_of_hosts: usize,
+  reserved: (u64, u64),
+) -> Result<Vec<String>, String> {
+  let (addr, prefix) = parse_cidr(cidr_block)?;
+  let size: u64 = 1 << (32 - prefix);
+  let network = (u32::from(addr) as u64) & !(size - 1);
+  let (head, tail) = reserved;
+
+  let mut ipaddrs: Vec<String> = vec![];
+  if size > head + tail {
+    for ipaddr in (network + head)..(network + size - tail) {
+      if ipaddrs.len() == num_of_hosts {
+        break;
+      }
+
+      let ipaddr = Ipv4Addr::from(ipaddr as u32);
+      let last_octet = ipaddr.octets()[3];
+      if last_octet != 0 && last_octet != 255 {
+        ipaddrs.push(ipaddr.to_string());
+      }
+    }
+  }
+
+  if ipaddrs.len() < num_of_hosts {
+    return Err(format!(
+      "There are only {} available addresses in {}, but {} hosts are required.",
+      ipaddrs.len(),
+      cidr_block,
+      num_of_hosts
+    ));
+  }
+
+  Ok(ipaddrs)
+}
+
+/// What terraform reports about the deployed instances, in the same order.
+#[derive(Debug)]
+pub struct Instances {
+  /// Empty for the instances without public IPs.
+  pub public_ips: Vec<String>,
+  pub private_ips: Vec<String>,
+  pub instance_ids: Vec<String>,
+}
+
+impl Instances {
+  /// The address which the instance is connected to, the public IP if any.
+  pub fn ipaddr(&self, idx: usize) -> &str {
+    match &self.public_ips[idx][..] {
+      "" => &self.private_ips[idx],
+      public_ip => public_ip,
+    }
+  }
+}
+
+/// One output of `terraform output -json`, such as {"value": [...], "type": ..., "sensitive": false}.
+#[derive(Debug, Deserialize)]
+struct OutputValue {
+  value: Value,
+}
+
+/// The items of a list output, empty items (null or "") are kept as "" if allow_empty.
+fn output_to_list(
+  outputs: &HashMap<String, OutputValue>,
+  name: &str,
+  allow_empty: bool,
+) -> Option<Result<Vec<String>, String>> {
+  let output = outputs.get(name)?;
+  let items = match output.value.as_array() {
+    Some(items) => items,
+    None => return Some(Err(format!("The {} output is not a list.", name))),
+  };
+
+  Some(
+    items
+      .iter()
+      .map(|item| match item {
+        Value::String(item) if item != "" => Ok(item.to_string()),
+        Value::String(_) | Value::Null if allow_empty => Ok("".to_string()),
+        _ => Err(format!("The {} output has an invalid item {}.", name, item)),
+      })
+      .collect(),
+  )
+}
+
+/// Parse the outputs of `terraform output -json` and check they match the config.
+pub fn parse_outputs(
+  outputs: &str,
+  provider: &dyn Provider,
+  data: &Config,
+) -> Result<Instances, String> {
+  let outputs: HashMap<String, OutputValue> =
+    serde_json::from_str(outputs).map_err(|msg| format!("Cannot parse terraform outputs, {}", msg))?;
+
+  // Instances without public IPs are reached by their private IPs, such as through a jump host.
+  let public_ips = match output_to_list(&outputs, provider.public_ips_output(), true) {
+    Some(public_ips) => public_ips?,
+    None => {
+      warn!(
+        "Not found the {} output, the instances are reached by their private IPs.",
+        provider.public_ips_output()
+      );
+      vec!["".to_string(); data.num_of_hosts]
+    }
+  };
+
+  let private_ips = match output_to_list(&outputs, provider.private_ips_output(), false) {
+    Some(private_ips) => {
+      let private_ips = private_ips?;
+      if data.ipaddrs.len() > 0 && data.ipaddrs != private_ips {
+        warn!("The private IPs of instances differ from the allocated ones, use the former.");
+      }
+      private_ips
+    }
+    None => {
+      return Err(format!(
+        "Not found the {} output, please define it in the template.",
+        provider.private_ips_output()
+      ))
+    }
+  };
+
+  let instance_ids = match output_to_list(&outputs, provider.instance_ids_output(), false) {
+    Some(instance_ids) => instance_ids?,
+    None => {
+      return Err(format!(
+        "Not found the {} output, please define it in the template.",
+        provider.instance_ids_output()
+      ))
+    }
+  };
+
+  for (name, items) in [
+    ("public IPs", &public_ips),
+    ("private IPs", &private_ips),
+    ("instance ids", &instance_ids),
+  ] {
+    if items.len() != data.num_of_hosts {
+      return Err(format!(
+        "Terraform reports {} {}, but {} hosts are required.",
+        items.len(),
+        name,
+        data.num_of_hosts
+      ));
     }
   }
+
+  Ok(Instances {
+    public_ips: public_ips,
+    private_ips: private_ips,
+    instance_ids: instance_ids,
+  })
 }
 
-pub fn gen_hosts(data: &Config, public_ips: &Vec<String>) -> Vec<Host> {
+pub fn gen_hosts(data: &Config, instances: &Instances, username: &str) -> Vec<Host> {
   let mut hosts: Vec<Host> = vec![];
-  for (idx, ipaddr) in data.ipaddrs.iter().enumerate() {
+  for (idx, hostname) in data.hostnames.iter().enumerate() {
     hosts.push(Host {
-      hostname: format!("biopoem{:03}", idx + 1),
-      private_ipaddr: ipaddr.clone(),
-      ipaddr: public_ips[idx].to_string(),
+      hostname: hostname.clone(),
+      private_ipaddr: instances.private_ips[idx].clone(),
+      ipaddr: instances.ipaddr(idx).to_string(),
       port: "22".to_string(),
-      username: "root".to_string(),
+      username: username.to_string(),
+      instance_id: instances.instance_ids[idx].clone(),
     })
   }
 
   hosts
 }
 
+/// Generate a new ed25519 host key for each hostname by ssh-keygen in a temporary directory, keys
+/// are never shared by instances or reused across runs. The directory is removed after the keys are read.
+pub fn gen_host_keys(hostnames: &Vec<String>) -> Result<Vec<HostKey>, String> {
+  let keydir = tempfile::tempdir()
+    .map_err(|msg| format!("Cannot create a temporary directory for host keys, {}", msg))?;
+
+  let mut host_keys: Vec<HostKey> = vec![];
+  for hostname in hostnames {
+    let keypath = keydir.path().join(hostname);
+    let output = Command::new("ssh-keygen")
+      .args(["-q", "-t", "ed25519", "-N", "", "-C"])
+      .arg(hostname)
+      .arg("-f")
+      .arg(&keypath)
+      .output()
+      .map_err(|msg| format!("Cannot run ssh-keygen, {}", msg))?;
+    if !output.status.success() {
+      return Err(format!(
+        "Cannot generate the host key of {}, {}",
+        hostname,
+        String::from_utf8_lossy(&output.stderr)
+      ));
+    }
+
+    let pubpath = keypath.with_extension("pub");
+    let private_key = fs::read_to_string(&keypath)
+      .map_err(|msg| format!("Cannot read {}, {}", keypath.display(), msg))?;
+    let public_key = fs::read_to_string(&pubpath)
+      .map_err(|msg| format!("Cannot read {}, {}", pubpath.display(), msg))?;
+    host_keys.push(HostKey {
+      private_key: private_key,
+      public_key: public_key.trim().to_string(),
+    });
+  }
+
+  keydir
+    .close()
+    .map_err(|msg| format!("Cannot remove the temporary directory of host keys, {}", msg))?;
+  Ok(host_keys)
+}
+
+/// The known_hosts file which trusts the host key of each instance on its public and private IPs.
+pub fn gen_known_hosts(instances: &Instances, host_keys: &Vec<HostKey>) -> String {
+  let mut lines: Vec<String> = vec![];
+  for (idx, host_key) in host_keys.iter().enumerate() {
+    // Such as ssh-ed25519 AAAA..., the comment is left out.
+    let key: Vec<&str> = host_key.public_key.split_whitespace().take(2).collect();
+    let ipaddrs: Vec<&str> = [&instances.public_ips[idx], &instances.private_ips[idx]]
+      .iter()
+      .map(|ipaddr| ipaddr.as_str())
+      .filter(|ipaddr| *ipaddr != "")
+      .collect();
+    lines.push(format!("{} {}", ipaddrs.join(","), key.join(" ")));
+  }
+
+  lines.join("\n") + "\n"
+}
+
 pub fn render_template(template: &str, data: &Config) -> Option<String> {
   let context = Context::from_serialize(data).unwrap();
   Some(Tera::one_off(template, &context, false).unwrap())
@@ -98,6 +383,7 @@ pub fn render_template(template: &str, data: &Config) -> Option<String> {
 pub fn run(
   command: &str,
   dir: &str,
+  provider: &dyn Provider,
   access_key: &str,
   secret_key: &str,
   region: &str,
@@ -107,14 +393,12 @@ pub fn run(
   commands.insert("apply", vec!["apply", "-auto-approve", "-input=false"]);
   commands.insert("show", vec!["show", "-json"]);
   commands.insert("destroy", vec!["destroy", "-auto-approve", "-input=false"]);
-  commands.insert("output", vec!["output", "-json", "public_ips"]);
+  commands.insert("output", vec!["output", "-json"]);
 
   let args = commands.get(command).unwrap();
 
   match Command::new("terraform")
-    .env("ALICLOUD_ACCESS_KEY", access_key)
-    .env("ALICLOUD_SECRET_KEY", secret_key)
-    .env("ALICLOUD_REGION", region)
+    .envs(provider.env(access_key, secret_key, region))
     .current_dir(dir)
     .args(args)
     .output()
@@ -128,3 +412,93 @@ pub fn run(
     }
   };
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_allocate_ipaddrs() {
+    assert_eq!(
+      allocate_ipaddrs("172.16.0.0/21", 3, (1, 3)).unwrap(),
+      vec!["172.16.0.1", "172.16.0.2", "172.16.0.3"]
+    );
+    // The host bits of the CIDR block are ignored.
+    assert_eq!(
+      allocate_ipaddrs("10.0.0.5/24", 2, (4, 1)).unwrap(),
+      vec!["10.0.0.4", "10.0.0.5"]
+    );
+  }
+
+  #[test]
+  fn test_allocate_ipaddrs_across_24() {
+    let ipaddrs = allocate_ipaddrs("10.0.0.0/23", 256, (1, 1)).unwrap();
+    assert_eq!(ipaddrs.len(), 256);
+    assert_eq!(ipaddrs[253], "10.0.0.254");
+    // 10.0.0.255 and 10.0.1.0 are skipped.
+    assert_eq!(ipaddrs[254], "10.0.1.1");
+    assert_eq!(ipaddrs[255], "10.0.1.2");
+  }
+
+  fn outputs(public_ips: &str) -> String {
+    format!(
+      r#"{{
+        "public_ips": {{"value": {}, "type": ["list", "string"], "sensitive": false}},
+        "private_ips": {{"value": ["10.0.0.4", "10.0.0.5"]}},
+        "instance_ids": {{"value": ["i-0001", "i-0002"]}}
+      }}"#,
+      public_ips
+    )
+  }
+
+  fn config() -> Config {
+    let provider = provider::get_provider("aws").unwrap();
+    Config::new(
+      provider.as_ref(),
+      "us-east-1",
+      "",
+      "10.0.0.0/24",
+      false,
+      2,
+      "ami",
+      "t3.medium",
+      "key",
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn test_parse_outputs() {
+    let provider = provider::get_provider("aws").unwrap();
+    let instances =
+      parse_outputs(&outputs(r#"["1.2.3.4", "1.2.3.5"]"#), provider.as_ref(), &config()).unwrap();
+    assert_eq!(instances.ipaddr(0), "1.2.3.4");
+    assert_eq!(instances.instance_ids, vec!["i-0001", "i-0002"]);
+  }
+
+  #[test]
+  fn test_parse_outputs_without_public_ips() {
+    let provider = provider::get_provider("aws").unwrap();
+    let instances = parse_outputs(&outputs(r#"["", null]"#), provider.as_ref(), &config()).unwrap();
+    assert_eq!(instances.ipaddr(0), "10.0.0.4");
+    assert_eq!(instances.ipaddr(1), "10.0.0.5");
+
+    let outputs = r#"{"private_ips": {"value": ["10.0.0.4", "10.0.0.5"]}, "instance_ids": {"value": ["i-0001", "i-0002"]}}"#;
+    let instances = parse_outputs(outputs, provider.as_ref(), &config()).unwrap();
+    assert_eq!(instances.ipaddr(1), "10.0.0.5");
+
+    let outputs = r#"{"private_ips": {"value": ["10.0.0.4", ""]}, "instance_ids": {"value": ["i-0001", "i-0002"]}}"#;
+    assert!(parse_outputs(outputs, provider.as_ref(), &config()).is_err());
+  }
+
+  #[test]
+  fn test_allocate_ipaddrs_too_few() {
+    assert_eq!(allocate_ipaddrs("10.0.0.0/29", 4, (1, 3)).unwrap().len(), 4);
+    assert_eq!(
+      allocate_ipaddrs("10.0.0.0/29", 5, (1, 3)).unwrap_err(),
+      "There are only 4 available addresses in 10.0.0.0/29, but 5 hosts are required."
+    );
+    assert!(allocate_ipaddrs("10.0.0.0/30", 1, (2, 2)).is_err());
+    assert!(allocate_ipaddrs("10.0.0.0/33", 1, (1, 1)).is_err());
+  }
+}
@@ -0,0 +1,156 @@
+/// The names which can be used by `--provider`.
+pub const PROVIDERS: [&str; 3] = ["alicloud", "aws", "openstack"];
+
+/// What differs between cloud platforms when deploying servers by terraform.
+pub trait Provider {
+  fn name(&self) -> &str;
+
+  /// Environment variables which the terraform provider reads credentials and region from.
+  fn env(&self, access_key: &str, secret_key: &str, region: &str) -> Vec<(&str, String)>;
+
+  /// The zone id in the platform's convention, such as cn-shanghai-a or us-east-1a.
+  fn zone(&self, region: &str, zone: &str) -> String;
+
+  /// The user which logs into the instances.
+  fn username(&self) -> &str;
+
+  fn default_image(&self) -> Option<&str> {
+    None
+  }
+
+  fn default_instance_type(&self) -> Option<&str> {
+    None
+  }
+
+  /// The terraform output which holds the public IPs of instances.
+  fn public_ips_output(&self) -> &str {
+    "public_ips"
+  }
+
+  /// The terraform output which holds the private IPs assigned by the platform.
+  fn private_ips_output(&self) -> &str {
+    "private_ips"
+  }
+
+  /// The terraform output which holds the ids of instances.
+  fn instance_ids_output(&self) -> &str {
+    "instance_ids"
+  }
+
+  /// How many addresses at the beginning and the end of a subnet can't be used by instances.
+  fn reserved_addresses(&self) -> (u64, u64) {
+    (1, 1)
+  }
+}
+
+pub struct Alicloud;
+
+impl Provider for Alicloud {
+  fn name(&self) -> &str {
+    "alicloud"
+  }
+
+  fn env(&self, access_key: &str, secret_key: &str, region: &str) -> Vec<(&str, String)> {
+    vec![
+      ("ALICLOUD_ACCESS_KEY", access_key.to_string()),
+      ("ALICLOUD_SECRET_KEY", secret_key.to_string()),
+      ("ALICLOUD_REGION", region.to_string()),
+    ]
+  }
+
+  fn zone(&self, region: &str, zone: &str) -> String {
+    format!("{}-{}", region, zone)
+  }
+
+  fn username(&self) -> &str {
+    "root"
+  }
+
+  /// The first one and the last three addresses of a vswitch.
+  fn reserved_addresses(&self) -> (u64, u64) {
+    (1, 3)
+  }
+
+  fn default_image(&self) -> Option<&str> {
+    Some("ubuntu_20_04_x64_20G_alibase_20220215.vhd")
+  }
+
+  fn default_instance_type(&self) -> Option<&str> {
+    Some("ecs.t6-c2m1.large")
+  }
+}
+
+pub struct Aws;
+
+impl Provider for Aws {
+  fn name(&self) -> &str {
+    "aws"
+  }
+
+  fn env(&self, access_key: &str, secret_key: &str, region: &str) -> Vec<(&str, String)> {
+    vec![
+      ("AWS_ACCESS_KEY_ID", access_key.to_string()),
+      ("AWS_SECRET_ACCESS_KEY", secret_key.to_string()),
+      ("AWS_REGION", region.to_string()),
+    ]
+  }
+
+  fn zone(&self, region: &str, zone: &str) -> String {
+    format!("{}{}", region, zone)
+  }
+
+  /// The default user of Ubuntu AMIs.
+  fn username(&self) -> &str {
+    "ubuntu"
+  }
+
+  /// The first four and the last one addresses of a subnet.
+  fn reserved_addresses(&self) -> (u64, u64) {
+    (4, 1)
+  }
+
+  fn default_instance_type(&self) -> Option<&str> {
+    Some("t3.medium")
+  }
+}
+
+/// OpenStack reads the auth url and project from OS_* variables (such as an openrc file),
+/// only the user, password and region are set here.
+pub struct OpenStack;
+
+impl Provider for OpenStack {
+  fn name(&self) -> &str {
+    "openstack"
+  }
+
+  fn env(&self, access_key: &str, secret_key: &str, region: &str) -> Vec<(&str, String)> {
+    vec![
+      ("OS_USERNAME", access_key.to_string()),
+      ("OS_PASSWORD", secret_key.to_string()),
+      ("OS_REGION_NAME", region.to_string()),
+    ]
+  }
+
+  /// Availability zones aren't prefixed by regions, such as nova.
+  fn zone(&self, _region: &str, zone: &str) -> String {
+    zone.to_string()
+  }
+
+  fn username(&self) -> &str {
+    "ubuntu"
+  }
+
+  /// The network address, the gateway and the broadcast address.
+  fn reserved_addresses(&self) -> (u64, u64) {
+    (2, 1)
+  }
+}
+
+pub fn get_provider(name: &str) -> Option<Box<dyn Provider>> {
+  match name {
+    "alicloud" => Some(Box::new(Alicloud)),
+    "aws" => Some(Box::new(Aws)),
+    "openstack" => Some(Box::new(OpenStack)),
+    _ => None,
+  }
+}
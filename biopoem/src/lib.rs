@@ -2,7 +2,9 @@
 extern crate lazy_static;
 
 use log::{error, info};
+use sha2::{Digest, Sha256};
 use std::fs;
+use std::io;
 use std::path::Path;
 use std::process::{self, Output};
 use std::str;
@@ -15,6 +17,12 @@ pub const PROC_SUCCESS: i32 = 0;
 pub const PROC_PARSE_ERROR: i32 = 1;
 pub const PROC_EXEC_ERROR: i32 = 2;
 pub const PROC_OTHER_ERROR: i32 = 3;
+/// Some DAGs failed or were cancelled.
+pub const PROC_DAG_FAILED: i32 = 4;
+/// Some DAGs are still running.
+pub const PROC_DAG_RUNNING: i32 = 5;
+/// Some clients cannot be queried.
+pub const PROC_UNREACHABLE: i32 = 6;
 
 pub fn makedir(dir: &str) {
   if !Path::new(&dir).exists() {
@@ -45,6 +53,16 @@ pub fn vecu8_to_string(data: &Vec<u8>) -> String {
   return s;
 }
 
+pub fn sha256sum(filepath: &Path) -> Result<String, String> {
+  let mut file = fs::File::open(filepath)
+    .map_err(|msg| format!("Cannot open {}, {}", filepath.display(), msg))?;
+  let mut hasher = Sha256::new();
+  io::copy(&mut file, &mut hasher)
+    .map_err(|msg| format!("Cannot read {}, {}", filepath.display(), msg))?;
+
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
 pub fn handle_output(output: &Output) -> Status {
   info!("Output:\n{}", vecu8_to_string(&output.stdout));
 
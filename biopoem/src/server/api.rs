@@ -0,0 +1,208 @@
+use crate::client::logs::LogChunk;
+use crate::client::model::RunStatus;
+use log::info;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// Why the API of a client cannot be used.
+#[derive(Debug)]
+pub enum ApiError {
+  /// Nothing listens on the port, such as the client isn't running.
+  Refused(String),
+  /// The host doesn't answer in time.
+  Timeout(String),
+  Unreachable(String),
+  /// The ssh tunnel to the client cannot be opened.
+  Tunnel(String),
+  Unauthorized,
+  Http(u16, String),
+  InvalidResponse(String),
+}
+
+impl fmt::Display for ApiError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ApiError::Refused(_) => write!(f, "Connection Refused"),
+      ApiError::Timeout(_) => write!(f, "Timeout"),
+      ApiError::Unreachable(_) => write!(f, "Connection Failed"),
+      ApiError::Tunnel(_) => write!(f, "Tunnel Failed"),
+      ApiError::Unauthorized => write!(f, "Authentication Failed"),
+      ApiError::Http(code, _) => write!(f, "HTTP {}", code),
+      ApiError::InvalidResponse(_) => write!(f, "Invalid Response"),
+    }
+  }
+}
+
+impl ApiError {
+  /// More details than the display, such as the error of the connection.
+  pub fn detail(&self) -> &str {
+    match self {
+      ApiError::Refused(msg)
+      | ApiError::Timeout(msg)
+      | ApiError::Unreachable(msg)
+      | ApiError::Tunnel(msg)
+      | ApiError::Http(_, msg)
+      | ApiError::InvalidResponse(msg) => msg,
+      ApiError::Unauthorized => "",
+    }
+  }
+
+  /// The request didn't reach the client, it may be retried.
+  pub fn is_unreachable(&self) -> bool {
+    match self {
+      ApiError::Refused(_)
+      | ApiError::Timeout(_)
+      | ApiError::Unreachable(_)
+      | ApiError::Tunnel(_) => true,
+      _ => false,
+    }
+  }
+}
+
+impl From<reqwest::Error> for ApiError {
+  fn from(err: reqwest::Error) -> Self {
+    if err.is_timeout() {
+      return ApiError::Timeout(err.to_string());
+    }
+
+    // The io error is wrapped by hyper, find it in the sources.
+    let mut source = err.source();
+    while let Some(cause) = source {
+      if let Some(io_err) = cause.downcast_ref::<io::Error>() {
+        match io_err.kind() {
+          io::ErrorKind::ConnectionRefused => return ApiError::Refused(err.to_string()),
+          io::ErrorKind::TimedOut => return ApiError::Timeout(err.to_string()),
+          _ => {}
+        }
+      }
+      source = cause.source();
+    }
+
+    ApiError::Unreachable(err.to_string())
+  }
+}
+
+/// How many times and how long a request is tried.
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+  /// Retries after the first failed attempt.
+  pub retries: usize,
+  /// The delay before the first retry, it doubles every time.
+  pub delay: Duration,
+}
+
+/// Such as https://192.168.0.1:3000, clients serve https with TLS.
+pub fn base_url(ipaddr: &str, port: u16, tls: bool) -> String {
+  let scheme = match tls {
+    true => "https",
+    false => "http",
+  };
+  format!("{}://{}:{}", scheme, ipaddr, port)
+}
+
+pub fn api_url(base_url: &str, path: &str, secret_key: &str) -> String {
+  format!("{}/{}?secret_key={}", base_url, path, secret_key)
+}
+
+pub async fn fetch_status(
+  client: &reqwest::Client,
+  base_url: &str,
+  secret_key: &str,
+) -> Result<RunStatus, ApiError> {
+  let url = api_url(base_url, "api/v1/status", secret_key);
+  let response = client.get(url).send().await.map_err(ApiError::from)?;
+
+  let status = response.status();
+  let body = response.text().await.unwrap_or_default();
+  match status {
+    reqwest::StatusCode::UNAUTHORIZED => Err(ApiError::Unauthorized),
+    _ if !status.is_success() => Err(ApiError::Http(status.as_u16(), body)),
+    _ => serde_json::from_str::<RunStatus>(&body)
+      .map_err(|msg| ApiError::InvalidResponse(format!("{}, {}", msg, body))),
+  }
+}
+
+/// fetch_status which is retried when the client is unreachable, the other errors are returned
+/// immediately.
+pub async fn fetch_status_with_retry(
+  client: &reqwest::Client,
+  base_url: &str,
+  secret_key: &str,
+  retry: Retry,
+) -> Result<RunStatus, ApiError> {
+  let mut delay = retry.delay;
+  let mut attempt = 0;
+  loop {
+    match fetch_status(client, base_url, secret_key).await {
+      Err(err) if err.is_unreachable() && attempt < retry.retries => {
+        attempt += 1;
+        info!(
+          "{}: {} {}, retry {}/{} in {:?}.",
+          base_url,
+          err,
+          err.detail(),
+          attempt,
+          retry.retries,
+          delay
+        );
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+      }
+      result => return result,
+    }
+  }
+}
+
+/// Follow the log of a client from the offset, or from the last lines without an offset.
+/// Every chunk is passed to on_chunk until the client closes the stream.
+pub async fn follow_log<F: FnMut(LogChunk)>(
+  client: &reqwest::Client,
+  base_url: &str,
+  secret_key: &str,
+  name: &str,
+  offset: Option<u64>,
+  lines: usize,
+  mut on_chunk: F,
+) -> Result<(), ApiError> {
+  let start = match offset {
+    Some(offset) => format!("from_offset={}", offset),
+    None => format!("lines={}", lines),
+  };
+  let url = format!(
+    "{}&follow=true&{}",
+    api_url(base_url, &format!("api/v1/log/{}", name), secret_key),
+    start
+  );
+  let mut response = client.get(url).send().await.map_err(ApiError::from)?;
+
+  let status = response.status();
+  if status == reqwest::StatusCode::UNAUTHORIZED {
+    return Err(ApiError::Unauthorized);
+  } else if !status.is_success() {
+    let body = response.text().await.unwrap_or_default();
+    return Err(ApiError::Http(status.as_u16(), body));
+  }
+
+  // Events are separated by a blank line, a chunk of the response may hold a part of an event.
+  let mut buffer: Vec<u8> = vec![];
+  loop {
+    match response.chunk().await {
+      Err(msg) => return Err(ApiError::from(msg)),
+      Ok(None) => return Ok(()),
+      Ok(Some(bytes)) => buffer.extend_from_slice(&bytes),
+    };
+
+    while let Some(pos) = buffer.windows(2).position(|w| w == b"\n\n") {
+      let event: Vec<u8> = buffer.drain(..pos + 2).collect();
+      for line in String::from_utf8_lossy(&event).lines() {
+        if let Some(data) = line.strip_prefix("data:") {
+          let chunk = serde_json::from_str::<LogChunk>(data.trim_start())
+            .map_err(|msg| ApiError::InvalidResponse(format!("{}, {}", msg, data)))?;
+          on_chunk(chunk);
+        }
+      }
+    }
+  }
+}
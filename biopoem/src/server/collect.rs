@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// The file in results/<hostname> which records what was fetched.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Files which are always collected besides the declared outputs.
+pub const DEFAULT_FILES: [&str; 4] = ["client.log", "init.log", "status", "status.json"];
+
+/// Files which the client rewrites in place, they are always downloaded in full.
+pub const REWRITTEN_FILES: [&str; 2] = ["status", "status.json"];
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Entry {
+  pub path: String,
+  pub size: u64,
+  pub transfer: String,
+  pub fetched_at: String,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+  pub hostname: String,
+  pub remote_workdir: String,
+  pub files: Vec<Entry>,
+  pub errors: Vec<String>,
+}
+
+impl Manifest {
+  pub fn new(hostname: &str, remote_workdir: &str) -> Self {
+    Manifest {
+      hostname: hostname.to_string(),
+      remote_workdir: remote_workdir.to_string(),
+      ..Default::default()
+    }
+  }
+
+  pub fn write(&self, dir: &Path) -> Result<(), String> {
+    let filepath = dir.join(MANIFEST_FILE);
+    let data = serde_json::to_string_pretty(self).unwrap();
+    fs::write(&filepath, data).map_err(|msg| format!("Cannot write {}, {}", filepath.display(), msg))
+  }
+}
+
+/// Characters which a glob may contain besides letters and digits. The globs are expanded by the
+/// remote shell, so anything else could run a command there.
+const GLOB_CHARS: &str = "._-+=,@%:/*?[]!";
+
+/// Only the wildcards `*`, `?` and `[...]` of the shell are allowed in a glob.
+pub fn check_glob(glob: &str) -> Result<(), String> {
+  match glob
+    .chars()
+    .find(|c| !c.is_ascii_alphanumeric() && !GLOB_CHARS.contains(*c))
+  {
+    Some(c) => Err(format!(
+      "Invalid glob {}, {:?} is not allowed, only letters, digits and {} are.",
+      glob, c, GLOB_CHARS
+    )),
+    None if glob.is_empty() => Err("Invalid glob, it is empty.".to_string()),
+    None => Ok(()),
+  }
+}
+
+/// Logs and outputs only grow, so their downloads can be resumed.
+pub fn is_resumable(remote_name: &str) -> bool {
+  !REWRITTEN_FILES.contains(&remote_name)
+}
+
+/// Where a remote file is saved in the local results directory, never outside of it.
+pub fn local_path(dir: &Path, remote_name: &str) -> PathBuf {
+  let mut filepath = dir.to_path_buf();
+  for component in Path::new(remote_name).components() {
+    if let Component::Normal(name) = component {
+      filepath.push(name);
+    }
+  }
+
+  filepath
+}
+
+/// The absolute path of a file which is listed in the remote working directory.
+pub fn remote_path(remote_workdir: &str, remote_name: &str) -> String {
+  if remote_name.starts_with('/') {
+    remote_name.to_string()
+  } else {
+    format!("{}/{}", remote_workdir, remote_name)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_check_glob() {
+    assert!(check_glob("*.bam").is_ok());
+    assert!(check_glob("outputs/sample_[0-9]?.vcf.gz").is_ok());
+    assert!(check_glob("").is_err());
+    assert!(check_glob("*.bam; rm -rf ~").is_err());
+    assert!(check_glob("$(reboot)").is_err());
+    assert!(check_glob("`id`").is_err());
+    assert!(check_glob("a|b").is_err());
+  }
+}
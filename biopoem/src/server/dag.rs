@@ -1,29 +1,300 @@
-use log::error;
-use serde_json::Value;
+use crate::server::factfile;
+use crate::server::host::Host;
+use serde_json::{json, Map, Value};
+use std::error::Error;
 use std::fs;
-use std::path::PathBuf;
+use std::path::Path;
 use tera::{Context, Tera};
 
-pub fn read_to_value(jsonfile: &PathBuf) -> Value {
-  let data = fs::read_to_string(jsonfile).unwrap();
-  serde_json::from_str(&data).unwrap()
+/// Sections of the variable file which are shared by all hosts, later ones take precedence.
+pub const GLOBAL_KEYS: [&str; 2] = ["defaults", "_global"];
+
+/// Formats of the variable file, json is the default for a file without extension.
+pub const FORMATS: [&str; 5] = ["json", "yaml", "toml", "csv", "tsv"];
+
+/// The format of the variable file by its extension, other extensions (such as txt) are ambiguous
+/// and the format must be given.
+pub fn detect_format(filepath: &Path) -> Result<&'static str, String> {
+  match filepath.extension().and_then(|ext| ext.to_str()) {
+    // The default variable file has no extension.
+    None | Some("json") => Ok("json"),
+    Some("yaml") | Some("yml") => Ok("yaml"),
+    Some("toml") => Ok("toml"),
+    Some("csv") => Ok("csv"),
+    Some("tsv") => Ok("tsv"),
+    Some(ext) => Err(format!(
+      "Cannot detect the format of {} by the extension {}",
+      filepath.display(),
+      ext
+    )),
+  }
+}
+
+/// A table whose hostname column is the key of every row, the other columns are the variables
+/// of the host and empty cells are left out. A row can be _global or defaults too.
+fn read_table(filepath: &Path, delimiter: u8) -> Result<Value, String> {
+  let mut rdr = csv::ReaderBuilder::new()
+    .delimiter(delimiter)
+    .trim(csv::Trim::All)
+    .from_path(filepath)
+    .map_err(|msg| format!("Cannot read {}, {}", filepath.display(), msg))?;
+
+  let headers = rdr
+    .headers()
+    .map_err(|msg| format!("Cannot read {}, {}", filepath.display(), msg))?
+    .clone();
+  if !headers.iter().any(|header| header == "hostname") {
+    return Err(format!("Not found hostname column in {}", filepath.display()));
+  }
+
+  let mut variables = Map::new();
+  for result in rdr.records() {
+    let record = result.map_err(|msg| format!("Cannot parse {}, {}", filepath.display(), msg))?;
+    let mut hostname = "";
+    let mut host = Map::new();
+    for (header, field) in headers.iter().zip(record.iter()) {
+      if header == "hostname" {
+        hostname = field;
+      } else if field != "" {
+        host.insert(header.to_string(), Value::String(field.to_string()));
+      }
+    }
+
+    if hostname == "" {
+      return Err(format!("Empty hostname in {}", filepath.display()));
+    }
+    variables.insert(hostname.to_string(), Value::Object(host));
+  }
+
+  Ok(Value::Object(variables))
+}
+
+/// Read the variable file in the format, all formats become the same context of templates.
+pub fn read_variables(filepath: &Path, format: &str) -> Result<Value, String> {
+  if format == "csv" || format == "tsv" {
+    let delimiter = match format {
+      "tsv" => b'\t',
+      _ => b',',
+    };
+    return read_table(filepath, delimiter);
+  }
+
+  let data = fs::read_to_string(filepath)
+    .map_err(|msg| format!("Cannot read {}, {}", filepath.display(), msg))?;
+  let value: Result<Value, String> = match format {
+    "yaml" => serde_yaml::from_str(&data).map_err(|msg| msg.to_string()),
+    "toml" => toml::from_str(&data).map_err(|msg| msg.to_string()),
+    _ => serde_json::from_str(&data).map_err(|msg| msg.to_string()),
+  };
+
+  match value {
+    Err(msg) => Err(format!("Cannot parse {} as {}, {}", filepath.display(), format, msg)),
+    Ok(value) if !value.is_object() => Err(format!(
+      "{} must be a mapping from hostnames to variables",
+      filepath.display()
+    )),
+    Ok(value) => Ok(value),
+  }
 }
 
 pub fn convert_to_ctx(v: Value) -> Context {
   Context::from_value(v).unwrap()
 }
 
-pub fn render_template(template: &str, jsonfile: &PathBuf, hostname: &str) -> Option<String> {
-  let v = read_to_value(jsonfile);
-  let all = convert_to_ctx(v);
+/// The variables which every host has, the variable file can override them.
+pub fn builtin_variables(host: &Host, host_index: usize, num_hosts: usize) -> Value {
+  json!({
+    "hostname": host.hostname(),
+    "ipaddr": host.ipaddr(),
+    "private_ipaddr": host.private_ipaddr().unwrap_or_default(),
+    "host_index": host_index,
+    "num_hosts": num_hosts,
+  })
+}
 
-  return match all.get(hostname) {
-    Some(host_context) => {
-      let context = convert_to_ctx(host_context.clone());
-      Some(Tera::one_off(template, &context, false).unwrap())
+/// Merge other into base, nested objects are merged too and the values of other win.
+fn merge(base: &mut Value, other: &Value) {
+  match (base, other) {
+    (Value::Object(base), Value::Object(other)) => {
+      for (key, value) in other {
+        match base.get_mut(key) {
+          Some(base_value) if base_value.is_object() && value.is_object() => {
+            merge(base_value, value)
+          }
+          _ => {
+            base.insert(key.clone(), value.clone());
+          }
+        }
+      }
     }
+    (base, other) => *base = other.clone(),
+  }
+}
+
+/// The context of the host, it is the built-in variables, the global sections and the section
+/// of the host from low to high precedence. None if there is neither a global section nor a
+/// section of the host.
+pub fn host_context(variables: &Value, hostname: &str, builtins: Value) -> Option<Value> {
+  let globals: Vec<&Value> = GLOBAL_KEYS
+    .iter()
+    .filter_map(|key| variables.get(*key))
+    .collect();
+  let host_variables = variables.get(hostname);
+  if globals.is_empty() && host_variables.is_none() {
+    return None;
+  }
+
+  let mut context = builtins;
+  for global in globals {
+    merge(&mut context, global);
+  }
+  if let Some(host_variables) = host_variables {
+    merge(&mut context, host_variables);
+  }
+
+  Some(context)
+}
+
+/// The message of the tera error and its sources, such as the undefined variable.
+fn tera_error(err: tera::Error) -> String {
+  let mut messages = vec![err.to_string()];
+  let mut source = err.source();
+  while let Some(cause) = source {
+    messages.push(cause.to_string());
+    source = cause.source();
+  }
+
+  messages.join(", ")
+}
+
+pub fn render_template(
+  template: &str,
+  variables: &Value,
+  hostname: &str,
+  builtins: Value,
+) -> Result<String, String> {
+  let host_context = match host_context(variables, hostname, builtins) {
+    Some(host_context) => host_context,
     None => {
-      None
+      return Err(format!(
+        "Not found {} or a global section in the variable file",
+        hostname
+      ));
     }
   };
+
+  let context = Context::from_value(host_context).map_err(tera_error)?;
+  Tera::one_off(template, &context, false).map_err(tera_error)
+}
+
+/// Render the DAG of the host and check it, returns all errors if it cannot be deployed.
+pub fn render_and_validate(
+  template: &str,
+  variables: &Value,
+  host: &Host,
+  host_index: usize,
+  num_hosts: usize,
+) -> Result<String, Vec<String>> {
+  let builtins = builtin_variables(host, host_index, num_hosts);
+  let content =
+    render_template(template, variables, host.hostname(), builtins).map_err(|msg| vec![msg])?;
+
+  let errors = factfile::validate(&content);
+  match errors.is_empty() {
+    true => Ok(content),
+    false => Err(errors),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_detect_format() {
+    assert_eq!(detect_format(Path::new("variables")), Ok("json"));
+    assert_eq!(detect_format(Path::new("variables.json")), Ok("json"));
+    assert_eq!(detect_format(Path::new("variables.yaml")), Ok("yaml"));
+    assert_eq!(detect_format(Path::new("variables.yml")), Ok("yaml"));
+    assert_eq!(detect_format(Path::new("variables.toml")), Ok("toml"));
+    assert_eq!(detect_format(Path::new("variables.csv")), Ok("csv"));
+    assert_eq!(detect_format(Path::new("variables.tsv")), Ok("tsv"));
+    assert!(detect_format(Path::new("variables.txt")).is_err());
+    assert!(detect_format(Path::new("variables.xlsx")).is_err());
+  }
+
+  #[test]
+  fn test_merge() {
+    let mut base = json!({
+      "threads": 2,
+      "reference": { "genome": "hg19", "index": "bwa" },
+      "samples": ["s1", "s2"]
+    });
+    let other = json!({
+      "threads": 8,
+      "reference": { "genome": "hg38" },
+      "samples": ["s3"],
+      "outdir": "/data"
+    });
+
+    merge(&mut base, &other);
+    assert_eq!(
+      base,
+      json!({
+        "threads": 8,
+        "reference": { "genome": "hg38", "index": "bwa" },
+        "samples": ["s3"],
+        "outdir": "/data"
+      })
+    );
+  }
+
+  #[test]
+  fn test_merge_replaces_non_objects() {
+    let mut base = json!({ "reference": "hg19" });
+    merge(&mut base, &json!({ "reference": { "genome": "hg38" } }));
+    assert_eq!(base, json!({ "reference": { "genome": "hg38" } }));
+
+    let mut base = json!({ "reference": { "genome": "hg38" } });
+    merge(&mut base, &json!({ "reference": null }));
+    assert_eq!(base, json!({ "reference": null }));
+  }
+
+  #[test]
+  fn test_host_context_precedence() {
+    let variables = json!({
+      "defaults": { "threads": 2, "reference": "hg19", "queue": "short" },
+      "_global": { "threads": 4, "reference": "hg38" },
+      "host1": { "threads": 8, "hostname": "renamed" }
+    });
+    let builtins = json!({ "hostname": "host1", "host_index": 0 });
+
+    let context = host_context(&variables, "host1", builtins).unwrap();
+    assert_eq!(
+      context,
+      json!({
+        "hostname": "renamed",
+        "host_index": 0,
+        "threads": 8,
+        "reference": "hg38",
+        "queue": "short"
+      })
+    );
+  }
+
+  #[test]
+  fn test_host_context_globals_only() {
+    let variables = json!({ "_global": { "threads": 4 } });
+    let builtins = json!({ "hostname": "host2" });
+
+    let context = host_context(&variables, "host2", builtins).unwrap();
+    assert_eq!(context, json!({ "hostname": "host2", "threads": 4 }));
+  }
+
+  #[test]
+  fn test_host_context_missing_host() {
+    let variables = json!({ "host1": { "threads": 8 } });
+    assert_eq!(host_context(&variables, "host2", json!({})), None);
+    assert!(host_context(&variables, "host1", json!({})).is_some());
+  }
 }
@@ -0,0 +1,274 @@
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// The schema of factfiles which factotum accepts.
+pub const SCHEMA_PREFIX: &str = "iglu:com.snowplowanalytics.factotum/factfile/jsonschema/1-";
+
+fn check_strings(value: &Value, field: &str, task: &str, errors: &mut Vec<String>) {
+  match value.as_array() {
+    Some(items) if items.iter().all(|item| item.is_string()) => {}
+    _ => errors.push(format!("{} of task {} must be a list of strings", field, task)),
+  }
+}
+
+fn check_codes(value: &Value, field: &str, task: &str, errors: &mut Vec<String>) {
+  match value.as_array() {
+    Some(items) if items.iter().all(|item| item.is_u64()) => {}
+    _ => errors.push(format!(
+      "onResult.{} of task {} must be a list of return codes",
+      field, task
+    )),
+  }
+}
+
+/// Tasks which are in a cycle of dependencies or depend on one, tasks are removed when all
+/// their dependencies are removed until nothing changes.
+fn find_cycle(depends: &HashMap<String, Vec<String>>) -> Vec<String> {
+  let mut left: HashSet<&str> = depends.keys().map(|name| name.as_str()).collect();
+  loop {
+    let ready: Vec<&str> = left
+      .iter()
+      .filter(|name| {
+        depends[**name]
+          .iter()
+          .all(|dep| !left.contains(dep.as_str()))
+      })
+      .map(|name| *name)
+      .collect();
+    if ready.is_empty() {
+      break;
+    }
+
+    for name in ready {
+      left.remove(name);
+    }
+  }
+
+  let mut cycle: Vec<String> = left.iter().map(|name| name.to_string()).collect();
+  cycle.sort();
+  cycle
+}
+
+/// Check the factfile against the schema of factotum, the dependencies of tasks must exist and
+/// must not be cyclic. Returns all errors, it is empty if the factfile is valid.
+pub fn validate(content: &str) -> Vec<String> {
+  let value: Value = match serde_json::from_str(content) {
+    Ok(value) => value,
+    Err(msg) => return vec![format!("Invalid JSON, {}", msg)],
+  };
+
+  let mut errors: Vec<String> = vec![];
+  match value["schema"].as_str() {
+    Some(schema) if schema.starts_with(SCHEMA_PREFIX) => {}
+    Some(schema) => errors.push(format!("Unknown schema {}", schema)),
+    None => errors.push("Not found schema".to_string()),
+  }
+
+  if !value["data"]["name"].is_string() {
+    errors.push("data.name must be a string".to_string());
+  }
+
+  let tasks = match value["data"]["tasks"].as_array() {
+    Some(tasks) if !tasks.is_empty() => tasks,
+    Some(_) => {
+      errors.push("data.tasks is empty".to_string());
+      return errors;
+    }
+    None => {
+      errors.push("data.tasks must be a list of tasks".to_string());
+      return errors;
+    }
+  };
+
+  let mut depends: HashMap<String, Vec<String>> = HashMap::new();
+  for (idx, task) in tasks.iter().enumerate() {
+    let name = match task["name"].as_str() {
+      Some(name) if name != "" => name.to_string(),
+      _ => {
+        errors.push(format!("The name of task #{} must be a non-empty string", idx + 1));
+        continue;
+      }
+    };
+
+    if depends.contains_key(&name) {
+      errors.push(format!("Duplicate task {}", name));
+      continue;
+    }
+
+    match task["executor"].as_str() {
+      Some("shell") => {}
+      _ => errors.push(format!("executor of task {} must be shell", name)),
+    }
+    if !task["command"].is_string() {
+      errors.push(format!("command of task {} must be a string", name));
+    }
+    check_strings(&task["arguments"], "arguments", &name, &mut errors);
+    check_strings(&task["dependsOn"], "dependsOn", &name, &mut errors);
+    let on_result = &task["onResult"];
+    check_codes(&on_result["terminateJobWithSuccess"], "terminateJobWithSuccess", &name, &mut errors);
+    check_codes(&on_result["continueJob"], "continueJob", &name, &mut errors);
+
+    let deps: Vec<String> = match task["dependsOn"].as_array() {
+      Some(deps) => deps
+        .iter()
+        .filter_map(|dep| dep.as_str())
+        .map(|dep| dep.to_string())
+        .collect(),
+      None => vec![],
+    };
+    depends.insert(name, deps);
+  }
+
+  let mut broken = false;
+  let mut names: Vec<&String> = depends.keys().collect();
+  names.sort();
+  for name in names {
+    for dep in &depends[name] {
+      if dep == name {
+        errors.push(format!("Task {} depends on itself", name));
+        broken = true;
+      } else if !depends.contains_key(dep) {
+        errors.push(format!("Task {} depends on unknown task {}", name, dep));
+        broken = true;
+      }
+    }
+  }
+
+  // Unknown dependencies would be taken as cycles, a task which depends on itself is reported already.
+  if !broken {
+    let cycle = find_cycle(&depends);
+    if !cycle.is_empty() {
+      errors.push(format!("Cyclic dependencies among tasks {}", cycle.join(", ")));
+    }
+  }
+
+  errors
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  /// A factfile in the format of factotum's samples, it pins the schema which validate accepts.
+  fn factfile(tasks: Value) -> Value {
+    json!({
+      "schema": "iglu:com.snowplowanalytics.factotum/factfile/jsonschema/1-0-0",
+      "data": {
+        "name": "Biopoem Testing",
+        "tasks": tasks
+      }
+    })
+  }
+
+  fn task(name: &str, depends_on: Vec<&str>) -> Value {
+    json!({
+      "name": name,
+      "executor": "shell",
+      "command": "wget",
+      "arguments": ["--no-check-certificate", "https://example.com/file"],
+      "dependsOn": depends_on,
+      "onResult": {
+        "terminateJobWithSuccess": [],
+        "continueJob": [0]
+      }
+    })
+  }
+
+  fn depends(edges: Vec<(&str, Vec<&str>)>) -> HashMap<String, Vec<String>> {
+    edges
+      .into_iter()
+      .map(|(name, deps)| (name.to_string(), deps.iter().map(|dep| dep.to_string()).collect()))
+      .collect()
+  }
+
+  #[test]
+  fn test_validate_canonical_factfile() {
+    let content = factfile(json!([
+      task("Download", vec![]),
+      task("Unpack", vec!["Download"]),
+      task("Count", vec!["Download"]),
+      task("Report", vec!["Unpack", "Count"])
+    ]));
+    assert_eq!(validate(&content.to_string()), Vec::<String>::new());
+  }
+
+  #[test]
+  fn test_validate_schema() {
+    let errors = validate("{");
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].starts_with("Invalid JSON, "));
+
+    let mut content = factfile(json!([task("Download", vec![])]));
+    content["schema"] = json!("iglu:com.example/factfile/jsonschema/1-0-0");
+    assert_eq!(
+      validate(&content.to_string()),
+      vec!["Unknown schema iglu:com.example/factfile/jsonschema/1-0-0"]
+    );
+
+    let content = json!({ "data": { "tasks": [] } });
+    assert_eq!(
+      validate(&content.to_string()),
+      vec!["Not found schema", "data.name must be a string", "data.tasks is empty"]
+    );
+  }
+
+  #[test]
+  fn test_validate_tasks() {
+    let mut bad = task("Download", vec![]);
+    bad["executor"] = json!("docker");
+    bad["arguments"] = json!(["-q", 1]);
+    bad["onResult"]["continueJob"] = json!(["0"]);
+    let content = factfile(json!([bad, task("Download", vec![]), { "command": "ls" }]));
+    assert_eq!(
+      validate(&content.to_string()),
+      vec![
+        "executor of task Download must be shell",
+        "arguments of task Download must be a list of strings",
+        "onResult.continueJob of task Download must be a list of return codes",
+        "Duplicate task Download",
+        "The name of task #3 must be a non-empty string",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_validate_dependencies() {
+    let content = factfile(json!([
+      task("Download", vec!["Download"]),
+      task("Unpack", vec!["Fetch"])
+    ]));
+    assert_eq!(
+      validate(&content.to_string()),
+      vec![
+        "Task Download depends on itself",
+        "Task Unpack depends on unknown task Fetch"
+      ]
+    );
+
+    let content = factfile(json!([
+      task("Download", vec![]),
+      task("Unpack", vec!["Download", "Report"]),
+      task("Report", vec!["Unpack"])
+    ]));
+    assert_eq!(
+      validate(&content.to_string()),
+      vec!["Cyclic dependencies among tasks Report, Unpack"]
+    );
+  }
+
+  #[test]
+  fn test_find_cycle() {
+    let acyclic = depends(vec![("a", vec![]), ("b", vec!["a"]), ("c", vec!["a", "b"])]);
+    assert_eq!(find_cycle(&acyclic), Vec::<String>::new());
+
+    // d depends on the cycle of b and c, it can't run either.
+    let cyclic = depends(vec![
+      ("a", vec![]),
+      ("b", vec!["a", "c"]),
+      ("c", vec!["b"]),
+      ("d", vec!["c"]),
+    ]);
+    assert_eq!(find_cycle(&cyclic), vec!["b", "c", "d"]);
+  }
+}
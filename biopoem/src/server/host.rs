@@ -1,12 +1,27 @@
 use serde::{Deserialize, Serialize};
 use std::fs::File;
 
+/// The port of the client API if the hosts file doesn't specify it.
+pub const DEFAULT_API_PORT: u16 = 3000;
+
+/// A row of the hosts file, private_ipaddr, api_port, api_address and jump_host are optional columns.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Host {
   hostname: String,
   ipaddr: String,
   port: String,
   username: String,
+  #[serde(default)]
+  private_ipaddr: Option<String>,
+  /// The port which the client listens on.
+  #[serde(default)]
+  api_port: Option<u16>,
+  /// public or private, which address is used for accessing the client API.
+  #[serde(default)]
+  api_address: Option<String>,
+  /// The ProxyJump of ssh, such as user@bastion:22, none for connecting the host directly.
+  #[serde(default)]
+  jump_host: Option<String>,
 }
 
 impl Host {
@@ -25,6 +40,26 @@ impl Host {
   pub fn username(&self) -> &str {
     &self.username
   }
+
+  pub fn private_ipaddr(&self) -> Option<&str> {
+    self.private_ipaddr.as_deref()
+  }
+
+  pub fn jump_host(&self) -> Option<&str> {
+    self.jump_host.as_deref().filter(|jump_host| *jump_host != "")
+  }
+
+  pub fn api_port(&self) -> u16 {
+    self.api_port.unwrap_or(DEFAULT_API_PORT)
+  }
+
+  /// The private address if api_address is private and the host has one, the public one otherwise.
+  pub fn api_ipaddr(&self) -> &str {
+    match (self.api_address.as_deref(), self.private_ipaddr()) {
+      (Some("private"), Some(private_ipaddr)) => private_ipaddr,
+      _ => &self.ipaddr,
+    }
+  }
 }
 
 pub fn read_hosts(filepath: &str) -> Vec<Host> {
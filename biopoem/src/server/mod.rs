@@ -1,3 +1,10 @@
 pub mod remote;
 pub mod host;
-pub mod dag;
\ No newline at end of file
+pub mod dag;
+pub mod factfile;
+pub mod state;
+pub mod collect;
+pub mod api;
+pub mod samples;
+pub mod tunnel;
+pub mod tls;
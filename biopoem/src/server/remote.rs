@@ -1,111 +1,925 @@
-use log::{error, info};
-use openssh::{Error, KnownHosts, Session, SessionBuilder};
-use std::path::PathBuf;
-use tokio::io::AsyncWriteExt;
+use log::{info, warn};
+use super::collect;
+use super::host::Host;
+use openssh::{Error, KnownHosts, Session, SessionBuilder, Stdio};
+use std::fmt;
+use std::str::FromStr;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{self, AsyncWriteExt};
+
+/// Where the biopoem binary on remote machine comes from.
+pub enum Binary {
+  /// Download the binary from the url on remote machine.
+  Url(String),
+  /// Upload the local binary, the checksum is its SHA-256.
+  Local { path: PathBuf, checksum: String },
+}
+
+/// The last step which a host reached during deployment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+  Pending,
+  Rendered,
+  Connected,
+  Uploaded,
+  Launched,
+}
+
+impl fmt::Display for Stage {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let stage = match self {
+      Stage::Pending => "Pending",
+      Stage::Rendered => "Rendered",
+      Stage::Connected => "Connected",
+      Stage::Uploaded => "Uploaded",
+      Stage::Launched => "Launched",
+    };
+
+    write!(f, "{}", stage)
+  }
+}
+
+pub const SUPERVISORS: [&str; 3] = ["nohup", "systemd-run", "systemd"];
+
+pub const RESTART_POLICIES: [&str; 3] = ["no", "on-failure", "always"];
+
+/// The systemd unit which runs the client.
+pub const UNIT_NAME: &str = "biopoem-client";
+
+/// The environment file of the client in remote_workdir, it holds the secret key which would be
+/// shown to all local users by `ps` and `systemctl show` on the command line.
+const ENV_FILE: &str = "client.env";
+
+/// What keeps the client running on remote machine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Supervisor {
+  /// A background process of the ssh session.
+  Nohup,
+  /// A transient systemd unit, it is gone after reboot.
+  SystemdRun,
+  /// An enabled systemd unit, it is started again after reboot.
+  Systemd,
+}
+
+impl fmt::Display for Supervisor {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let supervisor = match self {
+      Supervisor::Nohup => "nohup",
+      Supervisor::SystemdRun => "systemd-run",
+      Supervisor::Systemd => "systemd",
+    };
+
+    write!(f, "{}", supervisor)
+  }
+}
+
+impl FromStr for Supervisor {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "nohup" => Ok(Supervisor::Nohup),
+      "systemd-run" => Ok(Supervisor::SystemdRun),
+      "systemd" => Ok(Supervisor::Systemd),
+      _ => Err(format!("Unknown supervisor {}, must be one of {}", s, SUPERVISORS.join(", "))),
+    }
+  }
+}
+
+/// How the client is launched on remote machine.
+pub struct Supervision {
+  pub supervisor: Supervisor,
+  /// Restart= of the systemd unit, such as on-failure.
+  pub restart: String,
+  /// Environment variables of the client.
+  pub envs: Vec<(String, String)>,
+  /// The address which the client listens on, 127.0.0.1 keeps its API for ssh tunnels only.
+  pub api_host: String,
+  /// The client serves https with the certificate which init_env pushes.
+  pub tls: bool,
+  /// The client runs all tasks again instead of skipping the ones which succeeded before.
+  pub fresh: bool,
+}
+
+/// The certificate and the private key of the client, init_env pushes them to the host.
+pub struct TlsFiles {
+  pub cert: PathBuf,
+  pub key: PathBuf,
+}
+
+/// How a remote file was fetched.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transfer {
+  Downloaded,
+  Resumed,
+  Unchanged,
+}
+
+impl fmt::Display for Transfer {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:?}", self)
+  }
+}
+
+pub const HOST_KEY_POLICIES: [&str; 3] = ["strict", "accept-new", "accept"];
+
+/// How ssh connects remote machines.
+pub struct SshOptions {
+  /// The private key file for ssh.
+  pub keyfile: PathBuf,
+  /// strict, accept-new or accept.
+  pub host_key_policy: String,
+  /// The known_hosts file of the run.
+  pub known_hosts: PathBuf,
+  /// The ProxyJump of hosts without the jump_host column, such as user@bastion:22.
+  pub jump_host: Option<String>,
+  /// The ssh_config file which is written by write_config.
+  pub config_file: PathBuf,
+}
+
+impl SshOptions {
+  /// strict refuses hosts which are not in the known_hosts file, accept-new adds them into it,
+  /// accept trusts any host key.
+  pub fn new(
+    keyfile: &Path,
+    policy: &str,
+    known_hosts: &Path,
+    jump_host: Option<&str>,
+    config_file: &Path,
+  ) -> Result<Self, String> {
+    if !HOST_KEY_POLICIES.contains(&policy) {
+      return Err(format!(
+        "Unknown host key policy {}, must be one of {}",
+        policy,
+        HOST_KEY_POLICIES.join(", ")
+      ));
+    }
+
+    if policy == "strict" && !known_hosts.exists() {
+      return Err(format!(
+        "Not found {}, the strict host key policy needs the known host keys",
+        known_hosts.display()
+      ));
+    }
+
+    Ok(SshOptions {
+      keyfile: keyfile.to_path_buf(),
+      host_key_policy: policy.to_string(),
+      known_hosts: known_hosts.to_path_buf(),
+      jump_host: jump_host.filter(|jump_host| *jump_host != "").map(|jump_host| jump_host.to_string()),
+      config_file: config_file.to_path_buf(),
+    })
+  }
+
+  /// The ProxyJump of the host, None if it is connected directly.
+  pub fn jump_host_of<'a>(&'a self, host: &'a Host) -> Option<&'a str> {
+    jump_host_of(host, self.jump_host.as_deref())
+  }
+
+  /// Write the ssh_config file, ssh checks the host keys against the known_hosts file only.
+  /// The jump hosts are connected with the same keyfile, so it is in the config too.
+  pub fn write_config(&self, hosts: &Vec<Host>) -> Result<(), String> {
+    let strict_host_key_checking = match &self.host_key_policy[..] {
+      "strict" => "yes",
+      "accept-new" => "accept-new",
+      _ => "no",
+    };
+
+    let mut jump_hosts: Vec<String> = vec![];
+    let mut blocks: Vec<String> = vec![];
+    for host in hosts {
+      if let Some(jump_host) = host.jump_host() {
+        blocks.push(format!("Host {}\n  ProxyJump {}\n", host.ipaddr(), jump_host));
+        jump_hosts.extend(jump_hostnames(jump_host));
+      }
+    }
+    if let Some(jump_host) = &self.jump_host {
+      jump_hosts.extend(jump_hostnames(jump_host));
+    }
+    jump_hosts.sort();
+    jump_hosts.dedup();
+    jump_hosts.retain(|jump_host| jump_host != "none");
+    // Jump hosts themselves are connected directly.
+    if !jump_hosts.is_empty() {
+      blocks.insert(0, format!("Host {}\n  ProxyJump none\n", jump_hosts.join(" ")));
+    }
+
+    let mut defaults = vec![
+      format!("  IdentityFile \"{}\"", self.keyfile.display()),
+      format!("  UserKnownHostsFile \"{}\"", self.known_hosts.display()),
+      "  GlobalKnownHostsFile /dev/null".to_string(),
+      "  HashKnownHosts no".to_string(),
+      format!("  StrictHostKeyChecking {}", strict_host_key_checking),
+    ];
+    if let Some(jump_host) = &self.jump_host {
+      defaults.push(format!("  ProxyJump {}", jump_host));
+    }
+    blocks.push(format!("Host *\n{}\n", defaults.join("\n")));
+
+    let content = format!("# Generated by biopoem.\n{}", blocks.join("\n"));
+    std::fs::write(&self.config_file, content)
+      .map_err(|msg| format!("Cannot write {}, {}", self.config_file.display(), msg))
+  }
+}
+
+/// The host of every jump of ProxyJump, such as bastion of user@bastion:2222.
+/// The ProxyJump of the host, the jump_host column wins over the default one and none connects
+/// the host directly.
+pub fn jump_host_of<'a>(host: &'a Host, default: Option<&'a str>) -> Option<&'a str> {
+  match host.jump_host().or(default.filter(|jump_host| *jump_host != "")) {
+    Some("none") | None => None,
+    Some(jump_host) => Some(jump_host),
+  }
+}
+
+fn jump_hostnames(jump_host: &str) -> Vec<String> {
+  jump_host
+    .split(',')
+    .map(|jump| {
+      let jump = jump.trim().trim_start_matches("ssh://");
+      let jump = jump.rsplit_once('@').map(|(_, jump)| jump).unwrap_or(jump);
+      let jump = jump.rsplit_once(':').map(|(jump, _)| jump).unwrap_or(jump);
+      jump.to_string()
+    })
+    .collect()
+}
+
+fn known_hosts_check(policy: &str) -> KnownHosts {
+  match policy {
+    "strict" => KnownHosts::Strict,
+    "accept-new" => KnownHosts::Add,
+    _ => KnownHosts::Accept,
+  }
+}
 
 pub async fn init_session(
   host: &str,
   port: u16,
   username: &str,
-  keyfile: &PathBuf,
+  ssh: &SshOptions,
 ) -> Result<Session, Error> {
   let mut session = SessionBuilder::default();
   session
     .user(username.to_string())
     .port(port)
-    .keyfile(keyfile)
-    .known_hosts_check(KnownHosts::Accept)
+    .keyfile(&ssh.keyfile)
+    .known_hosts_check(known_hosts_check(&ssh.host_key_policy))
+    .config_file(&ssh.config_file)
     .control_directory("/tmp");
 
   info!(
     "Connect {} with {}(user) and {}(keyfile)",
     host,
     username,
-    keyfile.display()
+    ssh.keyfile.display()
   );
   return session.connect(host).await;
 }
 
+/// Run `program args` on the remote machine, fail if it cannot be run or exits with non-zero.
+async fn execute(session: &Session, program: &str, args: &str) -> Result<Output, String> {
+  let cmd = format!("{} {}", program, args);
+  match session.command(program).raw_arg(args).output().await {
+    Err(msg) => Err(format!("Cannot run `{}`, {}", cmd, msg)),
+    Ok(output) => {
+      info!("{:?}", output);
+      if output.status.success() {
+        Ok(output)
+      } else {
+        Err(format!(
+          "`{}` failed with {}, {}",
+          cmd,
+          output.status,
+          String::from_utf8_lossy(&output.stderr).trim()
+        ))
+      }
+    }
+  }
+}
+
+/// Copy a local file to remote machine over the sftp channel.
+async fn upload_file(session: &Session, filepath: &Path, remote_path: &str) -> Result<(), String> {
+  let mut file = File::open(filepath)
+    .await
+    .map_err(|msg| format!("Cannot open {}, {}", filepath.display(), msg))?;
+
+  let mut sftp = session.sftp();
+  let mut w = sftp
+    .write_to(remote_path)
+    .await
+    .map_err(|msg| format!("Cannot open {} on remote machine, {}", remote_path, msg))?;
+
+  io::copy(&mut file, &mut w)
+    .await
+    .map_err(|msg| format!("Cannot upload {}, {}", filepath.display(), msg))?;
+
+  // flush and close the remote file, absorbing any final errors
+  w.close()
+    .await
+    .map_err(|msg| format!("Cannot upload {}, {}", filepath.display(), msg))?;
+
+  Ok(())
+}
+
+/// SHA-256 of a remote file, None if it doesn't exist or cannot be read.
+async fn remote_sha256sum(session: &Session, remote_path: &str) -> Option<String> {
+  match execute(session, "sha256sum", remote_path).await {
+    Err(_) => None,
+    Ok(output) => String::from_utf8_lossy(&output.stdout)
+      .split_whitespace()
+      .next()
+      .map(|checksum| checksum.to_string()),
+  }
+}
+
+async fn upload_binary(
+  session: &Session,
+  remote_workdir: &str,
+  filepath: &Path,
+  checksum: &str,
+) -> Result<(), String> {
+  let dest = format!("{}/biopoem", remote_workdir);
+  if remote_sha256sum(session, &dest).await.as_deref() == Some(checksum) {
+    info!("The biopoem binary on remote machine is up to date, skip uploading.");
+    return Ok(());
+  }
+
+  info!("Upload {} to {}.", filepath.display(), dest);
+  // Upload to a temporary file, so a running client never sees a partial binary.
+  let tmpfile = format!("{}.uploading", dest);
+  upload_file(session, filepath, &tmpfile).await?;
+
+  match remote_sha256sum(session, &tmpfile).await {
+    Some(remote_checksum) if remote_checksum == checksum => {}
+    remote_checksum => {
+      return Err(format!(
+        "Checksum mismatch of {}, expected {}, got {}",
+        tmpfile,
+        checksum,
+        remote_checksum.unwrap_or("nothing".to_string())
+      ));
+    }
+  }
+
+  execute(session, "mv", &format!("-f {} {}", tmpfile, dest)).await?;
+  Ok(())
+}
+
 pub async fn init_env(
   session: &Session,
   remote_workdir: &str,
   dag: &PathBuf,
-  biopoem_bin_url: &str,
-) {
+  binary: &Binary,
+  tls: Option<&TlsFiles>,
+) -> Result<(), String> {
   info!(
     "Create the working directory({}) on remote machine.",
     remote_workdir
   );
-  let output = session
-    .command("mkdir")
-    .raw_arg(format!("-p {}", remote_workdir))
-    .output()
-    .await
-    .unwrap();
-  info!("{:?}", output);
+  execute(session, "mkdir", &format!("-p {}", remote_workdir)).await?;
 
   info!("Upload dag.factfile.");
+  upload_file(
+    session,
+    dag,
+    &format!("{}/{}", remote_workdir, "dag.factfile"),
+  )
+  .await?;
+
+  match binary {
+    Binary::Url(biopoem_bin_url) => {
+      info!("Download biopoem binary.");
+      execute(
+        session,
+        "wget",
+        &format!("{} -O {}/{}", biopoem_bin_url, remote_workdir, "biopoem"),
+      )
+      .await?;
+    }
+    Binary::Local { path, checksum } => {
+      upload_binary(session, remote_workdir, path, checksum).await?;
+    }
+  }
+
+  execute(session, "chmod", &format!("a+x {}/biopoem", remote_workdir)).await?;
+
+  if let Some(tls) = tls {
+    info!("Upload the certificate and the private key of the client.");
+    upload_file(session, &tls.cert, &format!("{}/client.pem", remote_workdir)).await?;
+    // The key file is only readable by the owner before it is written.
+    let keyfile = format!("{}/client.key", remote_workdir);
+    execute(session, "install", &format!("-m 600 /dev/null {}", keyfile)).await?;
+    upload_file(session, &tls.key, &keyfile).await?;
+  }
+
+  Ok(())
+}
+
+/// Quote a value for the remote shell.
+fn quote(value: &str) -> String {
+  format!("'{}'", value.replace("'", "'\\''"))
+}
+
+/// Run `program args` as root, by sudo if the remote user is not root.
+async fn execute_privileged(session: &Session, program: &str, args: &str) -> Result<Output, String> {
+  let output = execute(session, "id", "-u").await?;
+  match String::from_utf8_lossy(&output.stdout).trim() {
+    "0" => execute(session, program, args).await,
+    _ => execute(session, "sudo", &format!("-n {} {}", program, args)).await,
+  }
+}
+
+/// Whether the remote machine is booted with systemd.
+async fn has_systemd(session: &Session) -> bool {
+  execute(session, "test", "-d /run/systemd/system").await.is_ok()
+}
+
+/// Write the content into a remote file over the sftp channel.
+async fn upload_content(session: &Session, content: &str, remote_path: &str) -> Result<(), String> {
   let mut sftp = session.sftp();
   let mut w = sftp
-    .write_to(format!("{}/{}", remote_workdir, "dag.factfile"))
+    .write_to(remote_path)
     .await
-    .unwrap();
-
-  let bytes = std::fs::read_to_string(dag).unwrap();
-  w.write_all(bytes.as_bytes()).await.unwrap();
+    .map_err(|msg| format!("Cannot open {} on remote machine, {}", remote_path, msg))?;
 
-  // flush and close the remote file, absorbing any final errors
-  w.close().await.unwrap();
-
-  info!("Download biopoem binary.");
-  match session
-    .command("wget")
-    .raw_arg(format!(
-      "{} -O {}/{}",
-      biopoem_bin_url, remote_workdir, "biopoem"
-    ))
-    .output()
+  w.write_all(content.as_bytes())
+    .await
+    .map_err(|msg| format!("Cannot write {}, {}", remote_path, msg))?;
+  w.close()
     .await
-  {
-    Err(msg) => {
-      error!("{:?}", msg);
+    .map_err(|msg| format!("Cannot write {}, {}", remote_path, msg))?;
+
+  Ok(())
+}
+
+/// Write the secret key into the environment file of the client, it is only readable by the
+/// owner before it is written.
+async fn upload_env(session: &Session, remote_workdir: &str, secret_key: &str) -> Result<(), String> {
+  let envfile = format!("{}/{}", remote_workdir, ENV_FILE);
+  execute(session, "install", &format!("-m 600 /dev/null {}", envfile)).await?;
+  upload_content(session, &env_file(secret_key), &envfile).await
+}
+
+fn env_file(secret_key: &str) -> String {
+  format!("BIOPOEM_SECRET_KEY={}\n", secret_key)
+}
+
+/// The command line of the client, the arguments are the same for all supervisors. The secret
+/// key is read from the environment file.
+fn client_command(
+  remote_workdir: &str,
+  webhook_url: &str,
+  api_host: &str,
+  port: u16,
+  tls: bool,
+  fresh: bool,
+) -> String {
+  // An empty value would make the client take the next flag as the webhook.
+  let webhook = match webhook_url {
+    "" => "".to_string(),
+    _ => format!(" --webhook {}", webhook_url),
+  };
+  let tls = match tls {
+    true => format!(
+      " --tls-cert {}/client.pem --tls-key {}/client.key",
+      remote_workdir, remote_workdir
+    ),
+    false => "".to_string(),
+  };
+  let fresh = match fresh {
+    true => " --fresh",
+    false => "",
+  };
+
+  format!(
+    "{}/biopoem client --workdir {} --host {}{} --port {} --dag dag.factfile{}{}",
+    remote_workdir, remote_workdir, api_host, webhook, port, tls, fresh
+  )
+}
+
+/// The unit file of the client for the systemd supervisor. The client runs as the ssh user as
+/// it does with nohup, so the files in remote_workdir keep their owner.
+fn unit_file(remote_workdir: &str, username: &str, command: &str, supervision: &Supervision) -> String {
+  let envs: Vec<String> = supervision
+    .envs
+    .iter()
+    .map(|(key, value)| format!("Environment=\"{}={}\"\n", key, value.replace("\"", "\\\"")))
+    .collect();
+
+  format!(
+    "[Unit]
+Description=Biopoem client in {workdir}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=simple
+User={user}
+WorkingDirectory={workdir}
+EnvironmentFile={workdir}/{env_file}
+{envs}ExecStart={command}
+Restart={restart}
+RestartSec=10
+StandardOutput=append:{workdir}/init.log
+StandardError=append:{workdir}/init.log
+
+[Install]
+WantedBy=multi-user.target
+",
+    workdir = remote_workdir,
+    user = username,
+    env_file = ENV_FILE,
+    envs = envs.join(""),
+    command = command,
+    restart = supervision.restart
+  )
+}
+
+fn nohup_args(remote_workdir: &str, command: &str, supervision: &Supervision) -> String {
+  let envs: Vec<String> = supervision
+    .envs
+    .iter()
+    .map(|(key, value)| format!("{}={}", key, quote(value)))
+    .collect();
+  let env = match envs.len() {
+    0 => "".to_string(),
+    _ => format!("env {} ", envs.join(" ")),
+  };
+  // The shell exports the variables of the environment file and is replaced by the client.
+  let script = format!("set -a && . {}/{} && exec {}{}", remote_workdir, ENV_FILE, env, command);
+
+  // Why must need 2>&1? More details on https://askubuntu.com/a/1129702
+  format!("sh -c {} > {}/init.log 2>&1 &", quote(&script), remote_workdir)
+}
+
+fn systemd_run_args(remote_workdir: &str, username: &str, command: &str, supervision: &Supervision) -> String {
+  let envs: Vec<String> = supervision
+    .envs
+    .iter()
+    .map(|(key, value)| format!("--setenv={}", quote(&format!("{}={}", key, value))))
+    .collect();
+
+  format!(
+    "--unit={unit} --collect -p User={user} -p Restart={restart} -p RestartSec=10 -p WorkingDirectory={workdir} -p EnvironmentFile={workdir}/{env_file} -p StandardOutput=append:{workdir}/init.log -p StandardError=append:{workdir}/init.log {envs} {command}",
+    unit = UNIT_NAME,
+    user = username,
+    restart = supervision.restart,
+    workdir = remote_workdir,
+    env_file = ENV_FILE,
+    envs = envs.join(" "),
+    command = command
+  )
+}
+
+fn install_unit_args(remote_workdir: &str) -> String {
+  format!(
+    "-m 0600 {}/{}.service /etc/systemd/system/{}.service",
+    remote_workdir, UNIT_NAME, UNIT_NAME
+  )
+}
+
+fn stop_unit_args() -> String {
+  format!(
+    "-c 'systemctl stop {unit}; systemctl disable {unit}; systemctl reset-failed {unit}; true' 2>/dev/null",
+    unit = UNIT_NAME
+  )
+}
+
+fn kill_args(remote_workdir: &str) -> String {
+  format!(
+    "pid in $(pgrep -f '{}/[b]iopoem client'); do kill -TERM -- -$(ps -o pgid= -p $pid | tr -d ' ') $pid; done; true",
+    remote_workdir
+  )
+}
+
+async fn launch_nohup(session: &Session, remote_workdir: &str, command: &str, supervision: &Supervision) -> Result<(), String> {
+  execute(session, "nohup", &nohup_args(remote_workdir, command, supervision)).await?;
+
+  Ok(())
+}
+
+async fn launch_systemd_run(session: &Session, remote_workdir: &str, username: &str, command: &str, supervision: &Supervision) -> Result<(), String> {
+  execute_privileged(
+    session,
+    "systemd-run",
+    &systemd_run_args(remote_workdir, username, command, supervision),
+  )
+  .await?;
+
+  Ok(())
+}
+
+async fn launch_systemd(session: &Session, remote_workdir: &str, username: &str, command: &str, supervision: &Supervision) -> Result<(), String> {
+  let staging = format!("{}/{}.service", remote_workdir, UNIT_NAME);
+  upload_content(session, &unit_file(remote_workdir, username, command, supervision), &staging).await?;
+
+  // The unit may contain secrets in the environment variables, only root can read it.
+  let installed = execute_privileged(session, "install", &install_unit_args(remote_workdir)).await;
+  execute(session, "rm", &format!("-f {}", staging)).await?;
+  installed?;
+
+  execute_privileged(session, "systemctl", "daemon-reload").await?;
+  execute_privileged(session, "systemctl", &format!("enable {}", UNIT_NAME)).await?;
+  execute_privileged(session, "systemctl", &format!("restart {}", UNIT_NAME)).await?;
+
+  Ok(())
+}
+
+/// Launch the client, returns the supervisor which is used actually. Hosts without systemd
+/// fall back to nohup.
+pub async fn launch_biopoem(
+  session: &Session,
+  remote_workdir: &str,
+  username: &str,
+  webhook_url: &str,
+  port: u16,
+  secret_key: &str,
+  supervision: &Supervision,
+) -> Result<Supervisor, String> {
+  let command = client_command(
+    remote_workdir,
+    webhook_url,
+    &supervision.api_host,
+    port,
+    supervision.tls,
+    supervision.fresh,
+  );
+  upload_env(session, remote_workdir, secret_key).await?;
+
+  let supervisor = match supervision.supervisor {
+    Supervisor::Nohup => Supervisor::Nohup,
+    supervisor => match has_systemd(session).await {
+      true => supervisor,
+      false => {
+        warn!("Not found systemd on remote machine, launch biopoem with nohup.");
+        Supervisor::Nohup
+      }
+    },
+  };
+
+  info!("Launch biopoem with {}...", supervisor);
+  match supervisor {
+    Supervisor::Nohup => launch_nohup(session, remote_workdir, &command, supervision).await?,
+    Supervisor::SystemdRun => launch_systemd_run(session, remote_workdir, username, &command, supervision).await?,
+    Supervisor::Systemd => launch_systemd(session, remote_workdir, username, &command, supervision).await?,
+  };
+
+  Ok(supervisor)
+}
+
+/// Kill the biopoem client in remote_workdir and its tasks, it is the process group of the
+/// client since nohup keeps the group of the launching shell. `[b]iopoem` keeps pgrep from
+/// matching the shell which runs this command. The systemd unit of the client is stopped
+/// and disabled first, otherwise systemd restarts it.
+pub async fn kill_biopoem(session: &Session, remote_workdir: &str) -> Result<(), String> {
+  info!("Kill biopoem client in {}...", remote_workdir);
+  if has_systemd(session).await {
+    let result = execute_privileged(session, "sh", &stop_unit_args()).await;
+    if let Err(msg) = result {
+      info!("Cannot stop the systemd unit {}, {}", UNIT_NAME, msg);
     }
-    Ok(output) => {
-      info!("{:?}", output);
+  }
+
+  execute(session, "for", &kill_args(remote_workdir)).await?;
+
+  Ok(())
+}
+
+/// What the server subcommand runs on a remote machine for deploying the client, in the order
+/// of init_env, kill_biopoem and launch_biopoem. The steps which depend on the remote machine
+/// are commented, the commands of root are run by `sudo -n` if the remote user is not root.
+pub fn plan(
+  remote_workdir: &str,
+  username: &str,
+  dag: &Path,
+  binary: &Binary,
+  webhook_url: &str,
+  port: u16,
+  secret_key: &str,
+  supervision: &Supervision,
+) -> Vec<String> {
+  let mut steps: Vec<String> = vec![
+    "# init_env".to_string(),
+    format!("mkdir -p {}", remote_workdir),
+    format!("# sftp: upload {} to {}/dag.factfile", dag.display(), remote_workdir),
+  ];
+
+  match binary {
+    Binary::Url(biopoem_bin_url) => {
+      steps.push(format!("wget {} -O {}/biopoem", biopoem_bin_url, remote_workdir));
     }
+    Binary::Local { path, checksum } => {
+      let dest = format!("{}/biopoem", remote_workdir);
+      steps.push(format!("# skip uploading if the checksum is {}", checksum));
+      steps.push(format!("sha256sum {}", dest));
+      steps.push(format!("# sftp: upload {} to {}.uploading", path.display(), dest));
+      steps.push(format!("sha256sum {}.uploading", dest));
+      steps.push(format!("mv -f {}.uploading {}", dest, dest));
+    }
+  }
+  steps.push(format!("chmod a+x {}/biopoem", remote_workdir));
+  if supervision.tls {
+    steps.push(format!("# sftp: upload the certificate of the client to {}/client.pem", remote_workdir));
+    steps.push(format!("install -m 600 /dev/null {}/client.key", remote_workdir));
+    steps.push(format!("# sftp: upload the private key of the client to {}/client.key", remote_workdir));
   }
 
-  match session
-    .command("chmod")
-    .raw_arg(format!("a+x {}/biopoem", remote_workdir))
-    .output()
-    .await
-  {
-    Err(msg) => {
-      error!("{:?}", msg);
+  steps.push("# kill_biopoem".to_string());
+  steps.push("# if systemd is found, as root".to_string());
+  steps.push(format!("sh {}", stop_unit_args()));
+  steps.push(format!("for {}", kill_args(remote_workdir)));
+
+  steps.push(format!("# launch_biopoem with {}", supervision.supervisor));
+  let command = client_command(
+    remote_workdir,
+    webhook_url,
+    &supervision.api_host,
+    port,
+    supervision.tls,
+    supervision.fresh,
+  );
+  steps.push(format!("install -m 600 /dev/null {}/{}", remote_workdir, ENV_FILE));
+  steps.push(format!("# sftp: upload {}/{}", remote_workdir, ENV_FILE));
+  steps.push(format!("#   {}", env_file(secret_key).trim_end()));
+  if supervision.supervisor != Supervisor::Nohup {
+    steps.push("# if systemd is not found, fall back to nohup".to_string());
+    steps.push(format!("nohup {}", nohup_args(remote_workdir, &command, supervision)));
+    steps.push("# otherwise, as root".to_string());
+  }
+  match supervision.supervisor {
+    Supervisor::Nohup => {
+      steps.push(format!("nohup {}", nohup_args(remote_workdir, &command, supervision)));
     }
-    Ok(output) => {
-      info!("{:?}", output);
+    Supervisor::SystemdRun => {
+      steps.push(format!("systemd-run {}", systemd_run_args(remote_workdir, username, &command, supervision)));
+    }
+    Supervisor::Systemd => {
+      steps.push(format!(
+        "# sftp: upload the unit file to {}/{}.service",
+        remote_workdir, UNIT_NAME
+      ));
+      for line in unit_file(remote_workdir, username, &command, supervision).lines() {
+        steps.push(format!("#   {}", line));
+      }
+      steps.push(format!("install {}", install_unit_args(remote_workdir)));
+      steps.push(format!("rm -f {}/{}.service", remote_workdir, UNIT_NAME));
+      steps.push("systemctl daemon-reload".to_string());
+      steps.push(format!("systemctl enable {}", UNIT_NAME));
+      steps.push(format!("systemctl restart {}", UNIT_NAME));
     }
   }
+
+  steps
 }
 
-pub async fn launch_biopoem(session: &Session, remote_workdir: &str, webhook_url: &str, port: u16) {
-  info!("Launch biopoem...");
-  // Why must need 2>&1? More details on https://askubuntu.com/a/1129702
-  match session
-    .command("nohup")
-    .raw_arg(format!(
-      "{}/biopoem client --workdir {} --host 0.0.0.0 --webhook {} --port {} --dag dag.factfile --secret_key biopoem-secret-key > {}/init.log 2>&1 &",
-      remote_workdir, remote_workdir, webhook_url, port, remote_workdir
-    ))
-    .output()
-    .await {
-      Err(msg) => {
-        error!("{:?}", msg);
-      },
-      Ok(output) => {
-        info!("{:?}", output);
+/// Overwrite the status file which is served by the client.
+pub async fn write_status(session: &Session, remote_workdir: &str, status: &str) -> Result<(), String> {
+  execute(
+    session,
+    "echo",
+    &format!("{} > {}/status", status, remote_workdir),
+  )
+  .await?;
+
+  Ok(())
+}
+
+/// Regular files which match the globs in remote_workdir, with their sizes.
+pub async fn list_files(
+  session: &Session,
+  remote_workdir: &str,
+  globs: &Vec<String>,
+) -> Result<Vec<(String, u64)>, String> {
+  for glob in globs {
+    collect::check_glob(glob)?;
+  }
+
+  // The globs are expanded by the remote shell, a glob without any match stays as it is.
+  let output = execute(
+    session,
+    "cd",
+    &format!(
+      "{} && for f in {}; do if [ -f \"$f\" ]; then stat -c '%s %n' \"$f\"; fi; done",
+      remote_workdir,
+      globs.join(" ")
+    ),
+  )
+  .await?;
+
+  let mut files: Vec<(String, u64)> = vec![];
+  for line in String::from_utf8_lossy(&output.stdout).lines() {
+    if let Some((size, name)) = line.split_once(' ') {
+      if let Ok(size) = size.parse::<u64>() {
+        if !files.iter().any(|(f, _)| f == name) {
+          files.push((name.to_string(), size));
+        }
       }
-    };
+    }
+  }
+
+  Ok(files)
+}
+
+/// The SHA-256 of the first size bytes of a remote file.
+async fn remote_prefix_sha256sum(session: &Session, remote_path: &str, size: u64) -> Option<String> {
+  let args = format!("-c {} {} | sha256sum", size, quote(remote_path));
+  match execute(session, "head", &args).await {
+    Err(_) => None,
+    Ok(output) => String::from_utf8_lossy(&output.stdout)
+      .split_whitespace()
+      .next()
+      .map(|checksum| checksum.to_string()),
+  }
+}
+
+/// Download a remote file. An append-only file (resumable) continues from the end of the local
+/// file if the local file is the beginning of the remote one, the others are downloaded in full.
+pub async fn download_file(
+  session: &Session,
+  remote_path: &str,
+  local_path: &Path,
+  size: u64,
+  resumable: bool,
+) -> Result<Transfer, String> {
+  let local_size = match std::fs::metadata(local_path) {
+    Ok(metadata) if resumable && metadata.len() > 0 && metadata.len() <= size => {
+      Some(metadata.len())
+    }
+    _ => None,
+  };
+
+  // The local file may be an older version of the remote one rather than a part of it.
+  let is_prefix = match local_size {
+    Some(local_size) => {
+      let local_checksum = crate::sha256sum(local_path).ok();
+      local_checksum.is_some()
+        && remote_prefix_sha256sum(session, remote_path, local_size).await == local_checksum
+    }
+    None => false,
+  };
+
+  let (offset, transfer) = match local_size {
+    Some(local_size) if is_prefix && local_size == size => return Ok(Transfer::Unchanged),
+    Some(local_size) if is_prefix => (local_size, Transfer::Resumed),
+    _ => (0, Transfer::Downloaded),
+  };
+
+  let mut file = OpenOptions::new()
+    .create(true)
+    .write(true)
+    .append(offset > 0)
+    .truncate(offset == 0)
+    .open(local_path)
+    .await
+    .map_err(|msg| format!("Cannot open {}, {}", local_path.display(), msg))?;
+
+  info!(
+    "Download {} to {} from {} bytes.",
+    remote_path,
+    local_path.display(),
+    offset
+  );
+  let mut child = session
+    .command("tail")
+    .raw_arg(format!("-c +{} {}", offset + 1, quote(remote_path)))
+    .stdout(Stdio::piped())
+    .spawn()
+    .await
+    .map_err(|msg| format!("Cannot read {}, {}", remote_path, msg))?;
+
+  let mut stdout = match child.stdout().take() {
+    Some(stdout) => stdout,
+    None => return Err(format!("Cannot read {}, no output", remote_path)),
+  };
+
+  io::copy(&mut stdout, &mut file)
+    .await
+    .map_err(|msg| format!("Cannot download {}, {}", remote_path, msg))?;
+  file
+    .flush()
+    .await
+    .map_err(|msg| format!("Cannot write {}, {}", local_path.display(), msg))?;
+
+  let status = child
+    .wait()
+    .await
+    .map_err(|msg| format!("Cannot download {}, {}", remote_path, msg))?;
+  if !status.success() {
+    return Err(format!("Cannot download {}, {}", remote_path, status));
+  }
+
+  // Logs may grow while downloading, so only a short file is an error.
+  let downloaded_size = std::fs::metadata(local_path).map(|m| m.len()).unwrap_or(0);
+  if downloaded_size < size {
+    return Err(format!(
+      "{} is incomplete, {} of {} bytes",
+      local_path.display(),
+      downloaded_size,
+      size
+    ));
+  }
+
+  Ok(transfer)
 }
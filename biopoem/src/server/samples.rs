@@ -0,0 +1,218 @@
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+pub const STRATEGIES: [&str; 3] = ["round-robin", "balanced", "per-host"];
+
+/// A row of the sample sheet, keyed by the column names.
+pub type Sample = BTreeMap<String, String>;
+
+/// How samples are assigned to hosts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+  /// The nth sample goes to the (n % num_hosts)th host.
+  RoundRobin,
+  /// Every sample goes to the host with the smallest total size of files so far, larger samples first.
+  Balanced,
+  /// Every host takes the number of consecutive samples.
+  PerHost(usize),
+}
+
+/// Read a CSV or TSV sample sheet, TSV if the extension is tsv or txt. It must have a sample_id column.
+pub fn read_sample_sheet(filepath: &Path) -> Result<Vec<Sample>, String> {
+  let delimiter = match filepath.extension().and_then(|ext| ext.to_str()) {
+    Some("tsv") | Some("txt") => b'\t',
+    _ => b',',
+  };
+
+  let mut rdr = csv::ReaderBuilder::new()
+    .delimiter(delimiter)
+    .trim(csv::Trim::All)
+    .from_path(filepath)
+    .map_err(|msg| format!("Cannot read {}, {}", filepath.display(), msg))?;
+
+  let headers = rdr
+    .headers()
+    .map_err(|msg| format!("Cannot read {}, {}", filepath.display(), msg))?;
+  if !headers.iter().any(|header| header == "sample_id") {
+    return Err(format!("Not found sample_id column in {}", filepath.display()));
+  }
+
+  let mut samples: Vec<Sample> = vec![];
+  for result in rdr.deserialize() {
+    let sample: Sample =
+      result.map_err(|msg| format!("Cannot parse {}, {}", filepath.display(), msg))?;
+    samples.push(sample);
+  }
+
+  Ok(samples)
+}
+
+/// The total size of the files in the columns of a sample.
+pub fn sample_size(sample: &Sample, columns: &Vec<String>) -> Result<u64, String> {
+  let mut size = 0;
+  for column in columns {
+    match sample.get(column) {
+      Some(filepath) if filepath != "" => {
+        size += fs::metadata(filepath)
+          .map_err(|msg| format!("Cannot get the size of {}, {}", filepath, msg))?
+          .len();
+      }
+      _ => {}
+    }
+  }
+
+  Ok(size)
+}
+
+/// The indexes of the samples which every host takes, the order of the sample sheet is kept.
+/// sizes are only used by the balanced strategy.
+pub fn assign(
+  num_samples: usize,
+  num_hosts: usize,
+  strategy: Strategy,
+  sizes: &Vec<u64>,
+) -> Result<Vec<Vec<usize>>, String> {
+  if num_hosts == 0 {
+    return Err("No hosts for the samples".to_string());
+  }
+
+  let mut assignment: Vec<Vec<usize>> = vec![vec![]; num_hosts];
+  match strategy {
+    Strategy::RoundRobin => {
+      for idx in 0..num_samples {
+        assignment[idx % num_hosts].push(idx);
+      }
+    }
+    Strategy::Balanced => {
+      let mut order: Vec<usize> = (0..num_samples).collect();
+      order.sort_by(|a, b| sizes[*b].cmp(&sizes[*a]).then(a.cmp(b)));
+
+      let mut totals: Vec<u64> = vec![0; num_hosts];
+      for idx in order {
+        let host = (0..num_hosts).min_by_key(|host| (totals[*host], *host)).unwrap();
+        totals[host] += sizes[idx];
+        assignment[host].push(idx);
+      }
+
+      for samples in assignment.iter_mut() {
+        samples.sort();
+      }
+    }
+    Strategy::PerHost(num) => {
+      if num == 0 {
+        return Err("The number of samples per host must be positive".to_string());
+      }
+
+      let needed = (num_samples + num - 1) / num;
+      if needed > num_hosts {
+        return Err(format!(
+          "{} samples need {} hosts with {} samples per host, only {} hosts",
+          num_samples, needed, num, num_hosts
+        ));
+      }
+
+      for idx in 0..num_samples {
+        assignment[idx / num].push(idx);
+      }
+    }
+  }
+
+  Ok(assignment)
+}
+
+/// Put the assigned samples into the variables as the samples list of every host, hosts without
+/// samples get an empty list so that their DAGs still render. The other variables of hosts are kept.
+pub fn gen_variables(
+  base: Value,
+  hostnames: &Vec<String>,
+  samples: &Vec<Sample>,
+  assignment: &Vec<Vec<usize>>,
+) -> Value {
+  let mut variables = match base {
+    Value::Object(map) => map,
+    _ => Map::new(),
+  };
+
+  for (hostname, indexes) in hostnames.iter().zip(assignment.iter()) {
+    let assigned: Vec<&Sample> = indexes.iter().map(|idx| &samples[*idx]).collect();
+    let host = variables
+      .entry(hostname.clone())
+      .or_insert_with(|| Value::Object(Map::new()));
+    match host.as_object_mut() {
+      Some(host) => {
+        host.insert("samples".to_string(), json!(assigned));
+      }
+      None => *host = json!({ "samples": assigned }),
+    }
+  }
+
+  Value::Object(variables)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sample(sample_id: &str) -> Sample {
+    let mut sample = Sample::new();
+    sample.insert("sample_id".to_string(), sample_id.to_string());
+    sample
+  }
+
+  #[test]
+  fn test_assign_round_robin() {
+    let assignment = assign(5, 2, Strategy::RoundRobin, &vec![]).unwrap();
+    assert_eq!(assignment, vec![vec![0, 2, 4], vec![1, 3]]);
+  }
+
+  #[test]
+  fn test_assign_balanced() {
+    let sizes = vec![10, 60, 20, 30];
+    let assignment = assign(4, 2, Strategy::Balanced, &sizes).unwrap();
+    assert_eq!(assignment, vec![vec![1], vec![0, 2, 3]]);
+  }
+
+  #[test]
+  fn test_assign_per_host() {
+    let assignment = assign(5, 3, Strategy::PerHost(2), &vec![]).unwrap();
+    assert_eq!(assignment, vec![vec![0, 1], vec![2, 3], vec![4]]);
+
+    assert!(assign(5, 2, Strategy::PerHost(2), &vec![]).is_err());
+    assert!(assign(5, 2, Strategy::PerHost(0), &vec![]).is_err());
+  }
+
+  #[test]
+  fn test_assign_idle_hosts() {
+    let assignment = assign(2, 4, Strategy::RoundRobin, &vec![]).unwrap();
+    assert_eq!(assignment, vec![vec![0], vec![1], vec![], vec![]]);
+
+    let assignment = assign(2, 3, Strategy::PerHost(2), &vec![]).unwrap();
+    assert_eq!(assignment, vec![vec![0, 1], vec![], vec![]]);
+
+    assert!(assign(2, 0, Strategy::RoundRobin, &vec![]).is_err());
+  }
+
+  #[test]
+  fn test_gen_variables() {
+    let base = json!({
+      "_global": { "reference": "hg38" },
+      "host1": { "threads": 4 }
+    });
+    let hostnames = vec!["host1".to_string(), "host2".to_string(), "host3".to_string()];
+    let samples = vec![sample("s1"), sample("s2")];
+    let assignment = assign(2, 3, Strategy::RoundRobin, &vec![]).unwrap();
+
+    let variables = gen_variables(base, &hostnames, &samples, &assignment);
+    assert_eq!(
+      variables,
+      json!({
+        "_global": { "reference": "hg38" },
+        "host1": { "threads": 4, "samples": [{ "sample_id": "s1" }] },
+        "host2": { "samples": [{ "sample_id": "s2" }] },
+        "host3": { "samples": [] }
+      })
+    );
+  }
+}
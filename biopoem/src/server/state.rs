@@ -0,0 +1,131 @@
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// The file in the working directory which records the state of a run.
+pub const STATE_FILE: &str = "run_state.json";
+
+/// What is known about a host in the run.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct HostState {
+  /// The last step reached by the server subcommand.
+  pub stage: String,
+  pub launched_at: Option<String>,
+  pub error: Option<String>,
+  /// The status of the DAG when the host was queried last time.
+  pub status: Option<String>,
+  pub queried_at: Option<String>,
+  /// The client of the last launch listens on 127.0.0.1, its API is reached through an ssh
+  /// tunnel.
+  #[serde(default)]
+  pub local_api: bool,
+  /// The client of the last launch serves https with a certificate signed by the CA in the
+  /// workdir.
+  #[serde(default)]
+  pub tls: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RunState {
+  secret_key: String,
+  #[serde(default)]
+  hosts: BTreeMap<String, HostState>,
+}
+
+impl RunState {
+  pub fn new() -> Self {
+    RunState {
+      secret_key: gen_secret_key(),
+      hosts: BTreeMap::new(),
+    }
+  }
+
+  pub fn secret_key(&self) -> &str {
+    &self.secret_key
+  }
+
+  /// Partial reruns may launch the clients of a run differently, so it is kept by host.
+  pub fn local_api(&self, hostname: &str) -> bool {
+    self.hosts.get(hostname).map_or(false, |host| host.local_api)
+  }
+
+  pub fn tls(&self, hostname: &str) -> bool {
+    self.hosts.get(hostname).map_or(false, |host| host.tls)
+  }
+
+  /// Whether the CA in the workdir is needed to verify any client.
+  pub fn any_tls(&self) -> bool {
+    self.hosts.values().any(|host| host.tls)
+  }
+
+  pub fn host(&self, hostname: &str) -> Option<&HostState> {
+    self.hosts.get(hostname)
+  }
+
+  pub fn record_launch(
+    &mut self,
+    hostname: &str,
+    stage: &str,
+    error: Option<String>,
+    local_api: bool,
+    tls: bool,
+  ) {
+    let host = self.hosts.entry(hostname.to_string()).or_default();
+    host.stage = stage.to_string();
+    host.launched_at = Some(chrono::Local::now().to_rfc3339());
+    host.error = error;
+    host.local_api = local_api;
+    host.tls = tls;
+    // The status of the previous launch is stale.
+    host.status = None;
+    host.queried_at = None;
+  }
+
+  pub fn record_status(&mut self, hostname: &str, status: &str) {
+    let host = self.hosts.entry(hostname.to_string()).or_default();
+    host.status = Some(status.to_string());
+    host.queried_at = Some(chrono::Local::now().to_rfc3339());
+  }
+
+  pub fn read(workdir: &str) -> Result<Self, String> {
+    let filepath = Path::new(workdir).join(STATE_FILE);
+    let data = fs::read_to_string(&filepath)
+      .map_err(|msg| format!("Cannot read {}, {}", filepath.display(), msg))?;
+    serde_json::from_str(&data)
+      .map_err(|msg| format!("Cannot parse {}, {}", filepath.display(), msg))
+  }
+
+  /// The state file holds the secret key, so only the owner can read it.
+  pub fn write(&self, workdir: &str) -> Result<(), String> {
+    let filepath = Path::new(workdir).join(STATE_FILE);
+    let data = serde_json::to_string_pretty(self).unwrap();
+    fs::write(&filepath, data)
+      .map_err(|msg| format!("Cannot write {}, {}", filepath.display(), msg))?;
+    fs::set_permissions(&filepath, fs::Permissions::from_mode(0o600))
+      .map_err(|msg| format!("Cannot set permissions of {}, {}", filepath.display(), msg))
+  }
+
+  /// Reuse the state of a previous run in the workdir, so the launched clients keep working.
+  pub fn load_or_create(workdir: &str) -> Result<Self, String> {
+    if Path::new(workdir).join(STATE_FILE).exists() {
+      Self::read(workdir)
+    } else {
+      let state = Self::new();
+      state.write(workdir)?;
+      Ok(state)
+    }
+  }
+}
+
+pub fn gen_secret_key() -> String {
+  let key: String = rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(32)
+    .map(char::from)
+    .collect();
+
+  format!("biopoem-{}", key)
+}
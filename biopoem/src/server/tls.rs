@@ -0,0 +1,113 @@
+use super::host::Host;
+use rcgen::{
+  BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair,
+  SanType,
+};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+/// The certificate of the CA in the working directory, query verifies clients against it.
+pub const CA_CERT: &str = "ca.pem";
+/// The private key of the CA in the working directory.
+pub const CA_KEY: &str = "ca.key";
+
+/// The self-signed CA of a run, it signs the certificates of all clients.
+pub struct Ca {
+  cert: Certificate,
+}
+
+impl Ca {
+  /// Reuse the CA of a previous run in the workdir, so the launched clients keep trusted.
+  pub fn load_or_create(workdir: &str) -> Result<Self, String> {
+    let certpath = Path::new(workdir).join(CA_CERT);
+    let keypath = Path::new(workdir).join(CA_KEY);
+    if certpath.exists() {
+      let cert = fs::read_to_string(&certpath)
+        .map_err(|msg| format!("Cannot read {}, {}", certpath.display(), msg))?;
+      let key = fs::read_to_string(&keypath)
+        .map_err(|msg| format!("Cannot read {}, {}", keypath.display(), msg))?;
+      let key_pair =
+        KeyPair::from_pem(&key).map_err(|msg| format!("Cannot parse {}, {}", keypath.display(), msg))?;
+      let params = CertificateParams::from_ca_cert_pem(&cert, key_pair)
+        .map_err(|msg| format!("Cannot parse {}, {}", certpath.display(), msg))?;
+      let cert = Certificate::from_params(params)
+        .map_err(|msg| format!("Cannot load the CA, {}", msg))?;
+      return Ok(Ca { cert: cert });
+    }
+
+    let mut params = CertificateParams::default();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name = DistinguishedName::new();
+    params
+      .distinguished_name
+      .push(DnType::CommonName, "Biopoem Run CA");
+    let cert =
+      Certificate::from_params(params).map_err(|msg| format!("Cannot generate the CA, {}", msg))?;
+
+    let pem = cert
+      .serialize_pem()
+      .map_err(|msg| format!("Cannot serialize the CA, {}", msg))?;
+    fs::write(&certpath, pem)
+      .map_err(|msg| format!("Cannot write {}, {}", certpath.display(), msg))?;
+    write_private(&keypath, &cert.serialize_private_key_pem())?;
+
+    Ok(Ca { cert: cert })
+  }
+
+  /// The certificate and the private key of the client on the host in PEM. It is valid for the
+  /// public and private IPs, the hostname and 127.0.0.1 which ssh tunnels use.
+  pub fn issue(&self, host: &Host) -> Result<(String, String), String> {
+    let mut names: Vec<&str> = vec![host.ipaddr(), "127.0.0.1", "localhost", host.hostname()];
+    if let Some(private_ipaddr) = host.private_ipaddr() {
+      names.push(private_ipaddr);
+    }
+
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params
+      .distinguished_name
+      .push(DnType::CommonName, host.hostname());
+    params.subject_alt_names = names
+      .iter()
+      .map(|name| match name.parse::<IpAddr>() {
+        Ok(ipaddr) => SanType::IpAddress(ipaddr),
+        Err(_) => SanType::DnsName(name.to_string()),
+      })
+      .collect();
+
+    let cert = Certificate::from_params(params)
+      .map_err(|msg| format!("Cannot generate the certificate of {}, {}", host.hostname(), msg))?;
+    let pem = cert
+      .serialize_pem_with_signer(&self.cert)
+      .map_err(|msg| format!("Cannot sign the certificate of {}, {}", host.hostname(), msg))?;
+
+    Ok((pem, cert.serialize_private_key_pem()))
+  }
+}
+
+/// Private keys are readable by the owner only, a new file is created so from the start. The
+/// mode of an existing file is not changed by open, so it is set again.
+pub fn write_private(filepath: &Path, content: &str) -> Result<(), String> {
+  let mut file = OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .mode(0o600)
+    .open(filepath)
+    .map_err(|msg| format!("Cannot write {}, {}", filepath.display(), msg))?;
+  fs::set_permissions(filepath, fs::Permissions::from_mode(0o600))
+    .map_err(|msg| format!("Cannot set permissions of {}, {}", filepath.display(), msg))?;
+  file
+    .write_all(content.as_bytes())
+    .map_err(|msg| format!("Cannot write {}, {}", filepath.display(), msg))
+}
+
+/// The CA certificate for verifying clients.
+pub fn read_ca_cert(filepath: &Path) -> Result<reqwest::Certificate, String> {
+  let pem = fs::read(filepath).map_err(|msg| format!("Cannot read {}, {}", filepath.display(), msg))?;
+  reqwest::Certificate::from_pem(&pem)
+    .map_err(|msg| format!("Cannot parse {}, {}", filepath.display(), msg))
+}
@@ -0,0 +1,211 @@
+use super::host::Host;
+use super::remote::SshOptions;
+use log::info;
+use std::fs::{self, File};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use tokio::time::{self, Duration};
+
+/// A local port forward of `ssh -N -L`, the ssh process is killed when it is dropped. The stderr
+/// of ssh is written to tunnel-<hostname>.log next to the ssh_config file.
+pub struct Tunnel {
+  child: Child,
+  local_port: u16,
+}
+
+impl Tunnel {
+  /// Forward a local port to remote_host:remote_port which is seen from the host, the host is
+  /// connected with the ssh_config file as sessions are, through its jump host if any.
+  pub async fn open(
+    ssh: &SshOptions,
+    host: &Host,
+    remote_host: &str,
+    remote_port: u16,
+  ) -> Result<Self, String> {
+    let local_port = TcpListener::bind("127.0.0.1:0")
+      .and_then(|listener| listener.local_addr())
+      .map_err(|msg| format!("Cannot find a free local port, {}", msg))?
+      .port();
+
+    // ssh writes to stderr as long as it runs, a pipe which is no longer read would block it.
+    let logpath = ssh
+      .config_file
+      .with_file_name(format!("tunnel-{}.log", host.hostname()));
+    let log = File::create(&logpath)
+      .map_err(|msg| format!("Cannot create {}, {}", logpath.display(), msg))?;
+
+    info!(
+      "Forward 127.0.0.1:{} to {}:{} on {}",
+      local_port,
+      remote_host,
+      remote_port,
+      host.ipaddr()
+    );
+    let mut child = Command::new("ssh")
+      .arg("-F")
+      .arg(&ssh.config_file)
+      .arg("-i")
+      .arg(&ssh.keyfile)
+      .args(["-p", host.port(), "-l", host.username()])
+      .args(["-o", "BatchMode=yes", "-o", "ExitOnForwardFailure=yes", "-N", "-L"])
+      .arg(format!("127.0.0.1:{}:{}:{}", local_port, remote_host, remote_port))
+      .arg(host.ipaddr())
+      .stdin(Stdio::null())
+      .stdout(Stdio::null())
+      .stderr(Stdio::from(log))
+      .spawn()
+      .map_err(|msg| format!("Cannot run ssh, {}", msg))?;
+
+    // The local port is listened after ssh is authenticated.
+    for _ in 0..100 {
+      if let Ok(Some(status)) = child.try_wait() {
+        let stderr = fs::read_to_string(&logpath).unwrap_or_default();
+        return Err(format!("Cannot open the tunnel, ssh {}, {}", status, stderr.trim()));
+      }
+
+      if TcpStream::connect(("127.0.0.1", local_port)).is_ok() {
+        return Ok(Tunnel {
+          child: child,
+          local_port: local_port,
+        });
+      }
+
+      time::sleep(Duration::from_millis(200)).await;
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+    Err(format!(
+      "Cannot open the tunnel to {} in 20 seconds, see {}",
+      host.ipaddr(),
+      logpath.display()
+    ))
+  }
+
+  pub fn local_port(&self) -> u16 {
+    self.local_port
+  }
+}
+
+impl Drop for Tunnel {
+  fn drop(&mut self) {
+    let _ = self.child.kill();
+    let _ = self.child.wait();
+  }
+}
+
+/// Where the client API of a host is reached, it keeps the tunnel open.
+pub struct Endpoint {
+  pub ipaddr: String,
+  pub port: u16,
+  tunnel: Option<Tunnel>,
+}
+
+impl Endpoint {
+  pub fn is_tunneled(&self) -> bool {
+    self.tunnel.is_some()
+  }
+}
+
+/// Hosts behind a jump host are reached by a tunnel, so are all hosts if their clients listen
+/// on 127.0.0.1 (local_api), the others directly. Without ssh options, hosts behind a jump host
+/// are tried directly, but local_api ones cannot be reached at all.
+pub async fn api_endpoint(
+  host: &Host,
+  ssh: Option<&SshOptions>,
+  local_api: bool,
+) -> Result<Endpoint, String> {
+  match ssh {
+    None if local_api => Err(format!(
+      "The client of {} listens on 127.0.0.1, --keyfile is required to reach it by an ssh tunnel",
+      host.hostname()
+    )),
+    Some(ssh) if local_api || ssh.jump_host_of(host).is_some() => {
+      let tunnel = Tunnel::open(ssh, host, "127.0.0.1", host.api_port()).await?;
+      Ok(Endpoint {
+        ipaddr: "127.0.0.1".to_string(),
+        port: tunnel.local_port(),
+        tunnel: Some(tunnel),
+      })
+    }
+    _ => Ok(Endpoint {
+      ipaddr: host.api_ipaddr().to_string(),
+      port: host.api_port(),
+      tunnel: None,
+    }),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::remote;
+  use serde_json::json;
+  use std::path::Path;
+
+  fn host(jump_host: Option<&str>) -> Host {
+    serde_json::from_value(json!({
+      "hostname": "biopoem001",
+      "ipaddr": "10.0.0.5",
+      "port": "22",
+      "username": "root",
+      "private_ipaddr": "10.0.0.5",
+      "api_port": 3001,
+      "jump_host": jump_host
+    }))
+    .unwrap()
+  }
+
+  fn ssh(jump_host: Option<&str>) -> SshOptions {
+    SshOptions::new(
+      Path::new("keyfile"),
+      "accept",
+      Path::new("known_hosts"),
+      jump_host,
+      Path::new("ssh_config"),
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn test_jump_host_of() {
+    assert_eq!(remote::jump_host_of(&host(None), None), None);
+    assert_eq!(remote::jump_host_of(&host(Some("")), Some("")), None);
+    assert_eq!(
+      remote::jump_host_of(&host(None), Some("user@bastion:22")),
+      Some("user@bastion:22")
+    );
+    // The jump_host column wins over the default one, none connects the host directly.
+    assert_eq!(
+      remote::jump_host_of(&host(Some("admin@gateway")), Some("user@bastion:22")),
+      Some("admin@gateway")
+    );
+    assert_eq!(remote::jump_host_of(&host(Some("none")), Some("user@bastion:22")), None);
+
+    assert_eq!(ssh(Some("user@bastion:22")).jump_host_of(&host(None)), Some("user@bastion:22"));
+    assert_eq!(ssh(Some("user@bastion:22")).jump_host_of(&host(Some("none"))), None);
+    assert_eq!(ssh(None).jump_host_of(&host(None)), None);
+  }
+
+  #[tokio::test]
+  async fn test_api_endpoint_direct() {
+    // Without ssh options, hosts behind a jump host are tried directly.
+    let endpoint = api_endpoint(&host(Some("user@bastion:22")), None, false).await.unwrap();
+    assert_eq!((endpoint.ipaddr.as_str(), endpoint.port), ("10.0.0.5", 3001));
+    assert!(!endpoint.is_tunneled());
+
+    let endpoint = api_endpoint(&host(None), Some(&ssh(None)), false).await.unwrap();
+    assert_eq!((endpoint.ipaddr.as_str(), endpoint.port), ("10.0.0.5", 3001));
+    assert!(!endpoint.is_tunneled());
+
+    let endpoint = api_endpoint(&host(Some("none")), Some(&ssh(Some("user@bastion:22"))), false)
+      .await
+      .unwrap();
+    assert!(!endpoint.is_tunneled());
+  }
+
+  #[tokio::test]
+  async fn test_api_endpoint_local_api_without_ssh() {
+    assert!(api_endpoint(&host(None), None, true).await.is_err());
+  }
+}